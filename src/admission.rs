@@ -0,0 +1,149 @@
+use std::{sync::Arc, time::Instant};
+
+use tokio::sync::{Mutex, mpsc};
+
+use crate::{
+    dead_letter::DeadLetter,
+    payment_processors::structs::PaymentProcessorDTO,
+    queue::QueuedPayment,
+    reconciliation::SuspectProcessors,
+    service::{self, PaymentDeadline, PaymentOutcome, process_payment},
+    shutdown::InFlightGuard,
+    structs::AppState,
+};
+
+struct Admitted {
+    payment: PaymentProcessorDTO,
+    in_flight: InFlightGuard,
+    admitted_at: Instant,
+}
+
+/// Payments accepted over HTTP, waiting for one of a fixed pool of workers. Bounded so a
+/// burst queues up to `capacity` payments instead of spawning a task for each.
+pub struct AdmissionQueue {
+    sender: mpsc::Sender<Admitted>,
+    receiver: Mutex<mpsc::Receiver<Admitted>>,
+}
+
+/// Room for one payment, reserved before it is accepted.
+pub struct AdmissionSlot<'a>(mpsc::Permit<'a, Admitted>);
+
+impl AdmissionSlot<'_> {
+    pub fn admit(self, payment: PaymentProcessorDTO, in_flight: InFlightGuard) {
+        self.0.send(Admitted {
+            payment,
+            in_flight,
+            admitted_at: Instant::now(),
+        });
+    }
+}
+
+impl AdmissionQueue {
+    pub fn new(capacity: usize) -> Self {
+        let (sender, receiver) = mpsc::channel(capacity);
+        Self {
+            sender,
+            receiver: Mutex::new(receiver),
+        }
+    }
+
+    /// Reserves room for a payment, or `None` when the queue is full.
+    pub fn try_reserve(&self) -> Option<AdmissionSlot<'_>> {
+        self.sender.try_reserve().ok().map(AdmissionSlot)
+    }
+
+    /// Payments waiting, counting slots reserved but not yet filled.
+    pub fn depth(&self) -> usize {
+        self.sender.max_capacity() - self.sender.capacity()
+    }
+
+    pub fn capacity(&self) -> usize {
+        self.sender.max_capacity()
+    }
+
+    async fn next(&self) -> Option<Admitted> {
+        self.receiver.lock().await.recv().await
+    }
+}
+
+/// Processes admitted payments one at a time. Only returns if the queue goes away.
+pub async fn run_worker(state: Arc<AppState>) {
+    let budget = state.payment_router.payment_budget;
+    while let Some(admitted) = state.admission.next().await {
+        // Time spent waiting for a worker counts against the budget.
+        let deadline = PaymentDeadline::new(admitted.admitted_at, budget);
+        let mut suspects = SuspectProcessors::default();
+        let processed = process_payment(
+            &state,
+            admitted.payment,
+            &mut suspects,
+            deadline,
+            Some(&admitted.in_flight),
+        )
+        .await;
+        let queued = QueuedPayment {
+            suspects,
+            ..QueuedPayment::new(admitted.payment)
+        };
+        let requeue = match processed {
+            Ok(PaymentOutcome::Processed(_)) => false,
+            Ok(PaymentOutcome::Rejected(error)) => {
+                eprintln!(
+                    "Payment {} rejected by the processor: {error}",
+                    admitted.payment.correlation_id
+                );
+                let dead_letter = DeadLetter::new(
+                    admitted.payment,
+                    suspects,
+                    1,
+                    error.status().as_u16(),
+                    error.to_string(),
+                );
+                !service::dead_letter(&state, &dead_letter).await
+            }
+            Ok(PaymentOutcome::DeadlineExceeded) => {
+                let dead_letter = DeadLetter::deadline_exceeded(admitted.payment, suspects, 1);
+                !service::dead_letter(&state, &dead_letter).await
+            }
+            // The queue workers take it from here, with backoff.
+            Ok(PaymentOutcome::NotProcessed) | Err(_) => true,
+        };
+        // Like a delivery, it also goes to the queue if the dead letters can't take it.
+        if requeue && let Err(e) = state.queue.push(queued).await {
+            eprintln!(
+                "Failed to queue payment {}: {e:?}",
+                admitted.payment.correlation_id
+            );
+        }
+        admitted.in_flight.finish();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use uuid::Uuid;
+
+    use super::*;
+    use crate::{money::Money, shutdown::InFlightPayments};
+
+    #[tokio::test]
+    async fn turns_payments_away_once_full() {
+        let admission = AdmissionQueue::new(1);
+        let in_flight = Arc::new(InFlightPayments::default());
+        let payment = PaymentProcessorDTO {
+            correlation_id: Uuid::from_u128(1),
+            amount: Money::from_cents(100),
+            requested_at: "2025-07-01T12:00:00Z".parse().unwrap(),
+        };
+
+        let slot = admission.try_reserve().unwrap();
+        assert!(admission.try_reserve().is_none());
+        slot.admit(payment, in_flight.track(payment));
+        assert_eq!((admission.depth(), admission.capacity()), (1, 1));
+
+        let admitted = admission.next().await.unwrap();
+        assert_eq!(admitted.payment.correlation_id, payment.correlation_id);
+        assert_eq!(admission.depth(), 0);
+        assert!(admission.try_reserve().is_some());
+    }
+}
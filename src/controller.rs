@@ -2,62 +2,127 @@ use std::sync::Arc;
 
 use axum::{
     Json,
-    extract::{self, State},
+    extract::{self, Path, State},
     http::StatusCode,
     response::IntoResponse,
 };
 use serde_json::json;
+use uuid::Uuid;
 
+use crate::payment_processors;
 use crate::{
-    error_handling::internal_error,
-    repository,
-    structs::{AppState, PaymentDTO, PaymentSummaryQuery},
+    error_handling::{AppError, internal_error},
+    queue::QueuedPayment,
+    structs::{AppState, DeadLetterQuery, PaymentDTO, PaymentSummaryQuery},
+    validation::{Validate, ValidatedJson},
 };
-use crate::{payment_processors, service::process_payment};
 
 pub async fn payments(
     State(state): State<Arc<AppState>>,
-    extract::Json(payload): extract::Json<PaymentDTO>,
-) -> Result<impl IntoResponse, (StatusCode, String)> {
+    ValidatedJson(payload): ValidatedJson<PaymentDTO>,
+) -> Result<impl IntoResponse, AppError> {
+    // Make room before claiming, so a payment turned away can be retried without a 409.
+    let slot = state.admission.try_reserve();
+    if slot.is_none() {
+        let queue_depth = state.queue.depth().await.map_err(|e| internal_error(&*e))?;
+        if queue_depth >= state.config.admission.max_queue_depth {
+            return Err(AppError::Unavailable {
+                message: "Too many payments waiting, try again later".to_string(),
+                retry_after_secs: state.config.admission.retry_after_secs,
+            });
+        }
+    }
+
+    let claimed = state
+        .payments
+        .claim(payload.correlation_id)
+        .await
+        .map_err(|e| internal_error(&*e))?;
+    if !claimed {
+        return Err(AppError::Conflict {
+            code: "duplicate_payment",
+            message: "Payment already accepted".to_string(),
+            field: Some("correlationId".to_string()),
+        });
+    }
+
     let transaction: payment_processors::structs::PaymentProcessorDTO = payload.into();
-    let state = state.clone();
-    tokio::spawn(async move {
-        let _ = process_payment(
-            &state.memory_database,
-            &state.http_client,
-            &state.redis_queue,
-            state.processor_health.clone(),
-            transaction,
-        )
-        .await;
-    });
+    match slot {
+        Some(slot) => slot.admit(transaction, state.in_flight.track(transaction)),
+        // The admission queue is full: let the queue workers pick it up instead.
+        None => {
+            if let Err(e) = state.queue.push(QueuedPayment::new(transaction)).await {
+                // Nothing will process the payment, so don't turn its retry away with a 409.
+                let correlation_id = transaction.correlation_id;
+                if let Err(release_error) = state.payments.release(correlation_id).await {
+                    eprintln!("Failed to release claim for {correlation_id}: {release_error:?}");
+                }
+                return Err(internal_error(&*e));
+            }
+        }
+    }
 
     Ok((StatusCode::ACCEPTED, "Payment request accepted"))
 }
 
+/// Reports how many payments are waiting, for monitoring.
+pub async fn queue_depth(
+    State(state): State<Arc<AppState>>,
+) -> Result<impl IntoResponse, AppError> {
+    let queue_depth = state.queue.depth().await.map_err(|e| internal_error(&*e))?;
+
+    Ok((
+        StatusCode::OK,
+        Json(json!({
+            "admission": {
+                "depth": state.admission.depth(),
+                "capacity": state.admission.capacity(),
+            },
+            "queue": queue_depth,
+        })),
+    ))
+}
+
 pub async fn payments_summary(
     State(state): State<Arc<AppState>>,
 
     extract::Query(query_params): extract::Query<PaymentSummaryQuery>,
-) -> Result<impl IntoResponse, (StatusCode, String)> {
-    let summary = repository::get_payments_summary(
-        &state.memory_database,
-        &state.database,
-        query_params.from,
-        query_params.to,
-    )
-    .await
-    .map_err(|e| internal_error(&*e))?;
+) -> Result<impl IntoResponse, AppError> {
+    let summary = state
+        .payments
+        .summary(query_params.from, query_params.to)
+        .await
+        .map_err(|e| internal_error(&*e))?;
 
     Ok((StatusCode::OK, Json(summary)))
 }
 
-pub async fn purge_payments(
+/// Computes the summary from both backends and reports whether they agree.
+pub async fn summary_consistency(
     State(state): State<Arc<AppState>>,
-) -> Result<impl IntoResponse, (StatusCode, String)> {
-    let conn = state.database.pool.get().await.map_err(internal_error)?;
+    extract::Query(query_params): extract::Query<PaymentSummaryQuery>,
+) -> Result<impl IntoResponse, AppError> {
+    let report = state
+        .payments
+        .check_consistency(query_params.from, query_params.to)
+        .await
+        .map_err(|e| internal_error(&*e))?
+        .ok_or_else(|| {
+            AppError::NotFound("The payment store has a single summary backend".to_string())
+        })?;
+
+    if !report.consistent {
+        eprintln!("Summary backends disagree: {report:?}");
+    }
+    Ok((StatusCode::OK, Json(report)))
+}
 
-    let rows_affected = repository::purge_payments(conn)
+pub async fn purge_payments(
+    State(state): State<Arc<AppState>>,
+) -> Result<impl IntoResponse, AppError> {
+    let rows_affected = state
+        .payments
+        .purge()
         .await
         .map_err(|e| internal_error(&*e))?;
 
@@ -66,3 +131,87 @@ pub async fn purge_payments(
         Json(json!({ "message": format!("Purged {} payments", rows_affected) })),
     ))
 }
+
+const DEFAULT_DEAD_LETTER_PAGE_SIZE: usize = 100;
+const MAX_DEAD_LETTER_PAGE_SIZE: usize = 1_000;
+
+pub async fn list_dead_letters(
+    State(state): State<Arc<AppState>>,
+    extract::Query(query_params): extract::Query<DeadLetterQuery>,
+) -> Result<impl IntoResponse, AppError> {
+    query_params.validate()?;
+    let limit = query_params
+        .limit
+        .unwrap_or(DEFAULT_DEAD_LETTER_PAGE_SIZE)
+        .min(MAX_DEAD_LETTER_PAGE_SIZE);
+    let dead_letters = state
+        .dead_letters
+        .list(query_params.offset.unwrap_or(0), limit)
+        .await
+        .map_err(|e| internal_error(&*e))?;
+
+    Ok((StatusCode::OK, Json(dead_letters)))
+}
+
+pub async fn get_dead_letter(
+    State(state): State<Arc<AppState>>,
+    Path(correlation_id): Path<Uuid>,
+) -> Result<impl IntoResponse, AppError> {
+    let dead_letter = state
+        .dead_letters
+        .get(correlation_id)
+        .await
+        .map_err(|e| internal_error(&*e))?
+        .ok_or_else(|| AppError::NotFound("Dead letter not found".to_string()))?;
+
+    Ok((StatusCode::OK, Json(dead_letter)))
+}
+
+pub async fn replay_dead_letter(
+    State(state): State<Arc<AppState>>,
+    Path(correlation_id): Path<Uuid>,
+) -> Result<impl IntoResponse, AppError> {
+    let replayed = state
+        .dead_letters
+        .replay(correlation_id)
+        .await
+        .map_err(|e| internal_error(&*e))?;
+    if !replayed {
+        return Err(AppError::NotFound("Dead letter not found".to_string()));
+    }
+
+    Ok((
+        StatusCode::ACCEPTED,
+        Json(json!({ "message": format!("Replayed payment {}", correlation_id) })),
+    ))
+}
+
+pub async fn replay_dead_letters(
+    State(state): State<Arc<AppState>>,
+) -> Result<impl IntoResponse, AppError> {
+    let replayed = state
+        .dead_letters
+        .replay_all()
+        .await
+        .map_err(|e| internal_error(&*e))?;
+
+    Ok((
+        StatusCode::ACCEPTED,
+        Json(json!({ "message": format!("Replayed {} payments", replayed) })),
+    ))
+}
+
+pub async fn purge_dead_letters(
+    State(state): State<Arc<AppState>>,
+) -> Result<impl IntoResponse, AppError> {
+    let purged = state
+        .dead_letters
+        .purge()
+        .await
+        .map_err(|e| internal_error(&*e))?;
+
+    Ok((
+        StatusCode::OK,
+        Json(json!({ "message": format!("Purged {} dead letters", purged) })),
+    ))
+}
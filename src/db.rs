@@ -1,13 +1,70 @@
-use bb8::Pool;
+use std::{
+    ops::Deref,
+    sync::{Arc, LazyLock},
+};
+
+use bb8::{ManageConnection, Pool};
 use bb8_postgres::PostgresConnectionManager;
 use bb8_redis::RedisConnectionManager;
-use bb8_redis::redis::AsyncCommands;
-use tokio_postgres::NoTls;
+use bb8_redis::redis::{AsyncCommands, Script};
+use tokio::sync::Notify;
+use tokio_postgres::{Client, NoTls, Statement};
+
+use crate::{
+    config::MemoryDatabaseConfig, payment_processors::service::PaymentProcessorServices,
+    repository, structs::PaymentDatabaseEntry, summary::BucketRange,
+};
+
+pub(crate) type PostgresConnectionPool = Pool<PreparedConnectionManager>;
+
+pub(crate) type PostgresPooledConnection<'a> = bb8::PooledConnection<'a, PreparedConnectionManager>;
+
+/// A Postgres client with the statements run on every flush prepared on it once, when the
+/// connection is made. Derefs to the client for everything else.
+pub(crate) struct PreparedClient {
+    client: Client,
+    pub bulk_insert: Statement,
+}
+
+impl Deref for PreparedClient {
+    type Target = Client;
+
+    fn deref(&self) -> &Client {
+        &self.client
+    }
+}
+
+/// Makes Postgres connections that come with their `PreparedClient` statements.
+#[derive(Debug)]
+pub(crate) struct PreparedConnectionManager(PostgresConnectionManager<NoTls>);
+
+impl PreparedConnectionManager {
+    pub(crate) fn new(manager: PostgresConnectionManager<NoTls>) -> Self {
+        Self(manager)
+    }
+}
+
+impl ManageConnection for PreparedConnectionManager {
+    type Connection = PreparedClient;
+    type Error = tokio_postgres::Error;
 
-pub(crate) type PostgresConnectionPool = Pool<PostgresConnectionManager<NoTls>>;
+    async fn connect(&self) -> Result<PreparedClient, tokio_postgres::Error> {
+        let client = self.0.connect().await?;
+        let bulk_insert = repository::prepare_bulk_insert(&client).await?;
+        Ok(PreparedClient {
+            client,
+            bulk_insert,
+        })
+    }
 
-pub(crate) type PostgresPooledConnection<'a> =
-    bb8::PooledConnection<'a, bb8_postgres::PostgresConnectionManager<tokio_postgres::NoTls>>;
+    async fn is_valid(&self, conn: &mut PreparedClient) -> Result<(), tokio_postgres::Error> {
+        self.0.is_valid(&mut conn.client).await
+    }
+
+    fn has_broken(&self, conn: &mut PreparedClient) -> bool {
+        self.0.has_broken(&mut conn.client)
+    }
+}
 
 #[derive(Debug, Clone)]
 pub(crate) struct PostgresDatabase {
@@ -22,24 +79,198 @@ impl PostgresDatabase {
 
 pub(crate) type MemoryDatabaseConnection = Pool<RedisConnectionManager>;
 
+const PURGE_BATCH_SIZE: usize = 1_000;
+
+// Hands the oldest entries to a flushing list, unless an earlier batch there was never acked,
+// in which case that batch is handed out again.
+static CLAIM_BATCH_SCRIPT: LazyLock<Script> = LazyLock::new(|| {
+    Script::new(
+        r"
+        if redis.call('LLEN', KEYS[2]) == 0 then
+            for _ = 1, tonumber(ARGV[1]) do
+                if not redis.call('RPOPLPUSH', KEYS[1], KEYS[2]) then
+                    break
+                end
+            end
+        end
+        local batch = redis.call('LRANGE', KEYS[2], 0, -1)
+        if #batch > 0 then
+            redis.call('SADD', KEYS[3], KEYS[2])
+        end
+        return batch
+        ",
+    )
+});
+
+// Buffers a processed payment for the flusher and counts it in its service's per-second
+// bucket, all at once. A payment already counted is neither counted nor buffered again.
+// The second's individual payments are kept as long as its counters, until a purge: they sum
+// the seconds a summary range only partly covers and tell which payments were counted.
+static INSERT_PAYMENT_SCRIPT: LazyLock<Script> = LazyLock::new(|| {
+    Script::new(
+        r"
+        local added = redis.call('ZADD', KEYS[2], 'NX', ARGV[3], ARGV[2])
+        if added == 0 then
+            return redis.call('LLEN', KEYS[1])
+        end
+        redis.call('HINCRBY', KEYS[3], ARGV[4] .. ':count', 1)
+        redis.call('HINCRBY', KEYS[3], ARGV[4] .. ':cents', ARGV[5])
+        redis.call('ZADD', KEYS[4], ARGV[4], ARGV[4])
+        return redis.call('LPUSH', KEYS[1], ARGV[1])
+        ",
+    )
+});
+
+// Sums the counters of the seconds wholly inside the range, then the individual payments
+// of the seconds it only partly covers, whose keys follow the first two. Returns {count, cents}.
+static BUCKET_SUMMARY_SCRIPT: LazyLock<Script> = LazyLock::new(|| {
+    Script::new(
+        r"
+        local count, cents = 0, 0
+        for _, second in ipairs(redis.call('ZRANGEBYSCORE', KEYS[2], ARGV[1], ARGV[2])) do
+            local bucket = redis.call('HMGET', KEYS[1], second .. ':count', second .. ':cents')
+            count = count + (tonumber(bucket[1]) or 0)
+            cents = cents + (tonumber(bucket[2]) or 0)
+        end
+        for i = 3, #KEYS do
+            for _, member in ipairs(redis.call('ZRANGEBYSCORE', KEYS[i], ARGV[3], ARGV[4])) do
+                local separator = string.find(member, '|', 1, true)
+                count = count + 1
+                cents = cents + tonumber(string.sub(member, separator + 1))
+            end
+        end
+        return {count, cents}
+        ",
+    )
+});
+
 #[derive(Debug, Clone)]
 pub struct MemoryDatabase {
     pub pool: MemoryDatabaseConnection,
     collection_name: String,
+    flushing_list: String,
+    flush_threshold: usize,
+    flush_requested: Arc<Notify>,
+    summary_prefix: String,
 }
 
 impl MemoryDatabase {
-    pub fn new(pool: MemoryDatabaseConnection) -> Self {
-        let collection_name = std::env::var("MEMORY_DATABASE_COLLECTION_NAME")
-            .unwrap_or_else(|_| "payments".to_string());
-
+    pub fn new(
+        pool: MemoryDatabaseConnection,
+        config: &MemoryDatabaseConfig,
+        instance_name: &str,
+    ) -> Self {
         Self {
             pool,
-            collection_name,
+            collection_name: config.collection_name.clone(),
+            flushing_list: format!("{}:flushing:{instance_name}", config.collection_name),
+            flush_threshold: config.flush_threshold,
+            flush_requested: Arc::new(Notify::new()),
+            summary_prefix: config.summary_key_prefix(),
+        }
+    }
+
+    /// Buffers `value` for the flusher and adds `entry` to its service's per-second summary bucket.
+    pub async fn insert(
+        &self,
+        value: &str,
+        entry: &PaymentDatabaseEntry,
+    ) -> Result<(), bb8_redis::redis::RedisError> {
+        let mut conn = self.pool.get().await.map_err(|e| {
+            bb8_redis::redis::RedisError::from((
+                bb8_redis::redis::ErrorKind::IoError,
+                "bb8 pool error",
+                e.to_string(),
+            ))
+        })?;
+        let processed_at_us = entry.requested_at.timestamp_micros();
+        let second = processed_at_us.div_euclid(1_000_000);
+        let len: usize = INSERT_PAYMENT_SCRIPT
+            .key(&self.collection_name)
+            .key(self.bucket_payments_key(entry.service, second))
+            .key(self.bucket_counters_key(entry.service))
+            .key(self.bucket_index_key(entry.service))
+            .arg(value)
+            .arg(format!("{}|{}", entry.correlation_id, entry.amount.cents()))
+            .arg(processed_at_us)
+            .arg(second)
+            .arg(entry.amount.cents())
+            .invoke_async(&mut *conn)
+            .await?;
+        if len >= self.flush_threshold {
+            self.flush_requested.notify_one();
+        }
+        Ok(())
+    }
+
+    /// Request count and total cents of `service` within `range`, from the summary buckets.
+    pub async fn bucket_summary(
+        &self,
+        service: PaymentProcessorServices,
+        range: &BucketRange,
+    ) -> Result<(i64, i64), bb8_redis::redis::RedisError> {
+        let mut conn = self.pool.get().await.map_err(|e| {
+            bb8_redis::redis::RedisError::from((
+                bb8_redis::redis::ErrorKind::IoError,
+                "bb8 pool error",
+                e.to_string(),
+            ))
+        })?;
+        let bound = |value: Option<i64>, unbounded: &str| {
+            value.map_or_else(|| unbounded.to_string(), |value| value.to_string())
+        };
+
+        let mut invocation = BUCKET_SUMMARY_SCRIPT.prepare_invoke();
+        invocation
+            .key(self.bucket_counters_key(service))
+            .key(self.bucket_index_key(service))
+            .arg(bound(range.first_full_second, "-inf"))
+            .arg(bound(range.last_full_second, "+inf"))
+            .arg(bound(range.from_us, "-inf"))
+            .arg(bound(range.to_us, "+inf"));
+        for second in &range.partial_seconds {
+            invocation.key(self.bucket_payments_key(service, *second));
+        }
+        invocation.invoke_async(&mut *conn).await
+    }
+
+    /// Drops every summary bucket of both services.
+    pub async fn purge_buckets(&self) -> Result<(), bb8_redis::redis::RedisError> {
+        use bb8_redis::redis::pipe;
+        let mut conn = self.pool.get().await.map_err(|e| {
+            bb8_redis::redis::RedisError::from((
+                bb8_redis::redis::ErrorKind::IoError,
+                "bb8 pool error",
+                e.to_string(),
+            ))
+        })?;
+        for service in [
+            PaymentProcessorServices::Default,
+            PaymentProcessorServices::Fallback,
+        ] {
+            let index_key = self.bucket_index_key(service);
+            let seconds: Vec<i64> = AsyncCommands::zrange(&mut *conn, &index_key, 0, -1).await?;
+            let mut keys: Vec<String> = seconds
+                .into_iter()
+                .map(|second| self.bucket_payments_key(service, second))
+                .collect();
+            keys.push(self.bucket_counters_key(service));
+            keys.push(index_key);
+
+            let mut pipeline = pipe();
+            pipeline.atomic();
+            for chunk in keys.chunks(PURGE_BATCH_SIZE) {
+                pipeline.del(chunk).ignore();
+            }
+            let _: () = pipeline.query_async(&mut *conn).await?;
         }
+        Ok(())
     }
 
-    pub async fn insert(&self, value: &str) -> Result<(), bb8_redis::redis::RedisError> {
+    /// Drops every payment still buffered for the flusher, including the batches any instance
+    /// has claimed but not acked yet and the entries quarantined as unreadable.
+    pub async fn purge_buffer(&self) -> Result<(), bb8_redis::redis::RedisError> {
+        use bb8_redis::redis::pipe;
         let mut conn = self.pool.get().await.map_err(|e| {
             bb8_redis::redis::RedisError::from((
                 bb8_redis::redis::ErrorKind::IoError,
@@ -47,11 +278,57 @@ impl MemoryDatabase {
                 e.to_string(),
             ))
         })?;
-        let _: () = AsyncCommands::lpush(&mut *conn, &self.collection_name, value).await?;
+
+        let registry = self.flushing_registry();
+        let flushing_lists: Vec<String> = AsyncCommands::smembers(&mut *conn, &registry).await?;
+        let mut pipeline = pipe();
+        pipeline
+            .atomic()
+            .del(&self.collection_name)
+            .ignore()
+            .del(&self.flushing_list)
+            .ignore()
+            .del(self.quarantine_key())
+            .ignore();
+        for chunk in flushing_lists.chunks(PURGE_BATCH_SIZE) {
+            pipeline.del(chunk).ignore();
+        }
+        pipeline.del(&registry).ignore();
+        let _: () = pipeline.query_async(&mut *conn).await?;
         Ok(())
     }
 
-    pub async fn pop_all(&self) -> Result<Vec<String>, bb8_redis::redis::RedisError> {
+    /// Resolves once enough entries are buffered to be worth flushing early.
+    pub async fn flush_requested(&self) {
+        self.flush_requested.notified().await;
+    }
+
+    /// Moves up to `max` entries into this instance's flushing list and returns them.
+    /// They stay there until `ack_batch`, so a failed flush loses nothing.
+    pub async fn claim_batch(
+        &self,
+        max: usize,
+    ) -> Result<Vec<String>, bb8_redis::redis::RedisError> {
+        let mut conn = self.pool.get().await.map_err(|e| {
+            bb8_redis::redis::RedisError::from((
+                bb8_redis::redis::ErrorKind::IoError,
+                "bb8 pool error",
+                e.to_string(),
+            ))
+        })?;
+
+        CLAIM_BATCH_SCRIPT
+            .key(&self.collection_name)
+            .key(&self.flushing_list)
+            .key(self.flushing_registry())
+            .arg(max)
+            .invoke_async(&mut *conn)
+            .await
+    }
+
+    /// Drops the batch returned by the last `claim_batch` once it is safely stored elsewhere,
+    /// moving its `rejected` entries to the quarantine list for someone to look at.
+    pub async fn ack_batch(&self, rejected: &[String]) -> Result<(), bb8_redis::redis::RedisError> {
         use bb8_redis::redis::pipe;
         let mut conn = self.pool.get().await.map_err(|e| {
             bb8_redis::redis::RedisError::from((
@@ -62,11 +339,57 @@ impl MemoryDatabase {
         })?;
 
         let mut pipeline = pipe();
+        pipeline.atomic();
+        if !rejected.is_empty() {
+            pipeline.lpush(self.quarantine_key(), rejected).ignore();
+        }
         pipeline
-            .lrange(&self.collection_name, 0, -1)
-            .del(&self.collection_name);
-        let result: (Vec<String>, ()) = pipeline.query_async(&mut *conn).await?;
-        Ok(result.0)
+            .del(&self.flushing_list)
+            .ignore()
+            .srem(self.flushing_registry(), &self.flushing_list)
+            .ignore();
+        let _: () = pipeline.query_async(&mut *conn).await?;
+        Ok(())
+    }
+
+    /// How many instances, this one included, hold a claimed batch that is not acked yet.
+    pub async fn batches_in_flight(&self) -> Result<usize, bb8_redis::redis::RedisError> {
+        let mut conn = self.pool.get().await.map_err(|e| {
+            bb8_redis::redis::RedisError::from((
+                bb8_redis::redis::ErrorKind::IoError,
+                "bb8 pool error",
+                e.to_string(),
+            ))
+        })?;
+        AsyncCommands::scard(&mut *conn, self.flushing_registry()).await
+    }
+
+    fn flushing_registry(&self) -> String {
+        format!("{}:flushing", self.collection_name)
+    }
+
+    /// Buffered entries that could not be read, kept for someone to look at.
+    fn quarantine_key(&self) -> String {
+        format!("{}:quarantine", self.collection_name)
+    }
+
+    fn bucket_prefix(&self, service: PaymentProcessorServices) -> String {
+        format!("{}:{}", self.summary_prefix, service)
+    }
+
+    /// Hash of `{second}:count` and `{second}:cents` fields.
+    fn bucket_counters_key(&self, service: PaymentProcessorServices) -> String {
+        self.bucket_prefix(service)
+    }
+
+    /// Sorted set of the seconds that have a bucket.
+    fn bucket_index_key(&self, service: PaymentProcessorServices) -> String {
+        format!("{}:seconds", self.bucket_prefix(service))
+    }
+
+    /// Sorted set of `{correlation_id}|{cents}` scored by processed-at microsecond.
+    fn bucket_payments_key(&self, service: PaymentProcessorServices, second: i64) -> String {
+        format!("{}:{}:payments", self.bucket_prefix(service), second)
     }
 
     // pub async fn get_all(&self) -> Result<Vec<String>, bb8_redis::redis::RedisError> {
@@ -82,8 +405,3 @@ impl MemoryDatabase {
     // }
 
 }
-
-pub(crate) const DEFAULT_DATABASE_URL: &str =
-    "host=db port=5432 password=postgres user=postgres dbname=rinha_2025_db";
-
-pub(crate) const DEFAULT_MEMORY_DATABASE_URL: &str = "redis://localhost:6379";
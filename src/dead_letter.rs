@@ -0,0 +1,254 @@
+use std::sync::LazyLock;
+
+use axum::http::StatusCode;
+use bb8::{Pool, PooledConnection};
+use bb8_redis::RedisConnectionManager;
+use bb8_redis::redis::{AsyncCommands, Script};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use uuid::Uuid;
+
+use crate::{
+    config::QueueConfig,
+    payment_processors::structs::PaymentProcessorDTO,
+    queue::{QueuedPayment, RedisQueue},
+    reconciliation::SuspectProcessors,
+};
+
+pub(crate) type DeadLetterConnection = Pool<RedisConnectionManager>;
+
+// Takes dead letter ARGV[1] out of hash KEYS[2] and index KEYS[3] and queues ARGV[2] in its
+// place: on list KEYS[1], or on stream KEYS[1] when ARGV[3] names the payload field.
+// Returns 0, queueing nothing, if the dead letter was already gone.
+static REPLAY_SCRIPT: LazyLock<Script> = LazyLock::new(|| {
+    Script::new(
+        r"
+        if redis.call('HDEL', KEYS[2], ARGV[1]) == 0 then
+            return 0
+        end
+        redis.call('ZREM', KEYS[3], ARGV[1])
+        if ARGV[3] == '' then
+            redis.call('LPUSH', KEYS[1], ARGV[2])
+        else
+            redis.call('XADD', KEYS[1], '*', ARGV[3], ARGV[2])
+        end
+        return 1
+        ",
+    )
+});
+
+/// A payment the workers gave up on, kept aside until it is replayed or purged.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct DeadLetter {
+    pub payment: PaymentProcessorDTO,
+    pub attempts: u32,
+    /// Processors that may have the payment already, asked again once it is replayed.
+    #[serde(default, skip_serializing_if = "SuspectProcessors::is_empty")]
+    pub suspects: SuspectProcessors,
+    #[serde(rename = "lastErrorStatus")]
+    pub last_error_status: u16,
+    #[serde(rename = "lastError")]
+    pub last_error: String,
+    #[serde(rename = "firstAttemptAt")]
+    pub first_attempt_at: DateTime<Utc>,
+    #[serde(rename = "deadLetteredAt")]
+    pub dead_lettered_at: DateTime<Utc>,
+}
+
+impl DeadLetter {
+    /// `payment`, given up on now after `attempts` attempts, the last of which failed with
+    /// `last_error`.
+    pub fn new(
+        payment: PaymentProcessorDTO,
+        suspects: SuspectProcessors,
+        attempts: u32,
+        last_error_status: u16,
+        last_error: String,
+    ) -> Self {
+        Self {
+            payment,
+            attempts,
+            suspects,
+            last_error_status,
+            last_error,
+            first_attempt_at: payment.requested_at,
+            dead_lettered_at: Utc::now(),
+        }
+    }
+
+    /// `payment`, given up on now for running out of its deadline budget.
+    pub fn deadline_exceeded(
+        payment: PaymentProcessorDTO,
+        suspects: SuspectProcessors,
+        attempts: u32,
+    ) -> Self {
+        Self::new(
+            payment,
+            suspects,
+            attempts,
+            StatusCode::GATEWAY_TIMEOUT.as_u16(),
+            "The payment ran out of its deadline budget".to_string(),
+        )
+    }
+
+    /// The payment as a replay queues it, suspects and all, with a fresh deadline budget.
+    pub fn queued(&self) -> QueuedPayment {
+        QueuedPayment {
+            suspects: self.suspects,
+            budget_started_at: Some(Utc::now()),
+            ..QueuedPayment::new(self.payment)
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct DeadLetterQueue {
+    pool: DeadLetterConnection,
+    collection_name: String,
+    /// Where replayed payments go. It lives in the same Redis, so a replay is one script.
+    queue: RedisQueue,
+}
+
+impl DeadLetterQueue {
+    pub fn new(pool: DeadLetterConnection, config: &QueueConfig) -> Self {
+        Self {
+            queue: RedisQueue::new(pool.clone(), config),
+            pool,
+            collection_name: config.dead_letter_name.clone(),
+        }
+    }
+
+    pub async fn push(&self, dead_letter: &DeadLetter) -> Result<(), bb8_redis::redis::RedisError> {
+        let mut conn = self.connection().await?;
+
+        let value = serde_json::to_string(dead_letter).map_err(|e| {
+            bb8_redis::redis::RedisError::from((
+                bb8_redis::redis::ErrorKind::ParseError,
+                "Serialization error",
+                e.to_string(),
+            ))
+        })?;
+        let id = dead_letter.payment.correlation_id.to_string();
+
+        let mut pipeline = redis::pipe();
+        pipeline
+            .atomic()
+            .hset(&self.collection_name, &id, value)
+            .ignore()
+            .zadd(
+                self.index_key(),
+                &id,
+                dead_letter.dead_lettered_at.timestamp_millis(),
+            )
+            .ignore();
+        let _: () = pipeline.query_async(&mut *conn).await?;
+        Ok(())
+    }
+
+    /// Lists dead letters, oldest first.
+    pub async fn list(
+        &self,
+        offset: usize,
+        limit: usize,
+    ) -> Result<Vec<DeadLetter>, bb8_redis::redis::RedisError> {
+        let Ok(start) = isize::try_from(offset) else {
+            return Ok(Vec::new());
+        };
+        if limit == 0 {
+            return Ok(Vec::new());
+        }
+        let stop = isize::try_from(offset.saturating_add(limit - 1)).unwrap_or(isize::MAX);
+
+        let mut conn = self.connection().await?;
+        let ids: Vec<String> =
+            AsyncCommands::zrange(&mut *conn, self.index_key(), start, stop).await?;
+        if ids.is_empty() {
+            return Ok(Vec::new());
+        }
+
+        let values: Vec<Option<String>> =
+            AsyncCommands::hmget(&mut *conn, &self.collection_name, &ids).await?;
+        values
+            .into_iter()
+            .flatten()
+            .map(|value| Self::parse(&value))
+            .collect()
+    }
+
+    pub async fn get(
+        &self,
+        correlation_id: Uuid,
+    ) -> Result<Option<DeadLetter>, bb8_redis::redis::RedisError> {
+        let mut conn = self.connection().await?;
+        let value: Option<String> = AsyncCommands::hget(
+            &mut *conn,
+            &self.collection_name,
+            correlation_id.to_string(),
+        )
+        .await?;
+        value.map(|value| Self::parse(&value)).transpose()
+    }
+
+    /// Sends a dead letter back to the work queue and removes it, in one step.
+    /// Returns `false` if it was not there.
+    pub async fn replay(&self, correlation_id: Uuid) -> Result<bool, bb8_redis::redis::RedisError> {
+        let Some(dead_letter) = self.get(correlation_id).await? else {
+            return Ok(false);
+        };
+        let payload = dead_letter.queued().to_json()?;
+        let (queue_key, payload_field) = self.queue.push_target();
+
+        let mut conn = self.connection().await?;
+        let replayed: i32 = REPLAY_SCRIPT
+            .key(queue_key)
+            .key(&self.collection_name)
+            .key(self.index_key())
+            .arg(correlation_id.to_string())
+            .arg(payload)
+            .arg(payload_field)
+            .invoke_async(&mut *conn)
+            .await?;
+        Ok(replayed == 1)
+    }
+
+    pub async fn purge(&self) -> Result<u64, bb8_redis::redis::RedisError> {
+        let mut conn = self.connection().await?;
+
+        let mut pipeline = redis::pipe();
+        pipeline
+            .atomic()
+            .hlen(&self.collection_name)
+            .del(&self.collection_name)
+            .ignore()
+            .del(self.index_key())
+            .ignore();
+        let (purged,): (u64,) = pipeline.query_async(&mut *conn).await?;
+        Ok(purged)
+    }
+
+    fn parse(value: &str) -> Result<DeadLetter, bb8_redis::redis::RedisError> {
+        serde_json::from_str(value).map_err(|e| {
+            bb8_redis::redis::RedisError::from((
+                bb8_redis::redis::ErrorKind::ParseError,
+                "Deserialization error",
+                e.to_string(),
+            ))
+        })
+    }
+
+    async fn connection(
+        &self,
+    ) -> Result<PooledConnection<'_, RedisConnectionManager>, bb8_redis::redis::RedisError> {
+        self.pool.get().await.map_err(|e| {
+            bb8_redis::redis::RedisError::from((
+                bb8_redis::redis::ErrorKind::IoError,
+                "bb8 pool error",
+                e.to_string(),
+            ))
+        })
+    }
+
+    fn index_key(&self) -> String {
+        format!("{}:index", self.collection_name)
+    }
+}
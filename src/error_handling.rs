@@ -1,9 +1,125 @@
-use axum::http::StatusCode;
+use std::fmt;
 
-pub fn internal_error<E>(err: E) -> (StatusCode, String)
+use axum::{
+    Json,
+    http::{HeaderValue, StatusCode, header},
+    response::{IntoResponse, Response},
+};
+use serde::Serialize;
+
+/// Errors returned by the HTTP handlers, rendered as a stable `{code, message, field}` envelope.
+#[derive(Debug)]
+pub enum AppError {
+    /// The body is not valid JSON, or not JSON at all.
+    BadRequest {
+        code: &'static str,
+        message: String,
+    },
+    /// The body parsed but a field holds an unacceptable value.
+    Validation {
+        code: &'static str,
+        message: String,
+        field: Option<String>,
+    },
+    UnsupportedMediaType(String),
+    NotFound(String),
+    Conflict {
+        code: &'static str,
+        message: String,
+        field: Option<String>,
+    },
+    Internal(String),
+    /// Too busy to take the request; the client should retry after `retry_after_secs`.
+    Unavailable {
+        message: String,
+        retry_after_secs: u64,
+    },
+}
+
+#[derive(Debug, Serialize)]
+struct ErrorEnvelope<'a> {
+    code: &'a str,
+    message: &'a str,
+    field: Option<&'a str>,
+}
+
+impl AppError {
+    pub fn validation(code: &'static str, message: impl Into<String>, field: &str) -> Self {
+        AppError::Validation {
+            code,
+            message: message.into(),
+            field: Some(field.to_string()),
+        }
+    }
+
+    pub fn status(&self) -> StatusCode {
+        match self {
+            AppError::BadRequest { .. } => StatusCode::BAD_REQUEST,
+            AppError::Validation { .. } => StatusCode::UNPROCESSABLE_ENTITY,
+            AppError::UnsupportedMediaType(_) => StatusCode::UNSUPPORTED_MEDIA_TYPE,
+            AppError::NotFound(_) => StatusCode::NOT_FOUND,
+            AppError::Conflict { .. } => StatusCode::CONFLICT,
+            AppError::Internal(_) => StatusCode::INTERNAL_SERVER_ERROR,
+            AppError::Unavailable { .. } => StatusCode::SERVICE_UNAVAILABLE,
+        }
+    }
+
+    fn envelope(&self) -> ErrorEnvelope<'_> {
+        let (code, message, field) = match self {
+            AppError::BadRequest { code, message } => (*code, message, None),
+            AppError::Validation {
+                code,
+                message,
+                field,
+            }
+            | AppError::Conflict {
+                code,
+                message,
+                field,
+            } => (*code, message, field.as_deref()),
+            AppError::UnsupportedMediaType(message) => ("unsupported_media_type", message, None),
+            AppError::NotFound(message) => ("not_found", message, None),
+            AppError::Internal(message) => ("internal_error", message, None),
+            AppError::Unavailable { message, .. } => ("unavailable", message, None),
+        };
+        ErrorEnvelope {
+            code,
+            message,
+            field,
+        }
+    }
+}
+
+impl fmt::Display for AppError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let envelope = self.envelope();
+        match envelope.field {
+            Some(field) => write!(f, "{} ({}): {}", envelope.code, field, envelope.message),
+            None => write!(f, "{}: {}", envelope.code, envelope.message),
+        }
+    }
+}
+
+impl std::error::Error for AppError {}
+
+impl IntoResponse for AppError {
+    fn into_response(self) -> Response {
+        let mut response = (self.status(), Json(self.envelope())).into_response();
+        if let AppError::Unavailable {
+            retry_after_secs, ..
+        } = &self
+        {
+            response
+                .headers_mut()
+                .insert(header::RETRY_AFTER, HeaderValue::from(*retry_after_secs));
+        }
+        response
+    }
+}
+
+pub fn internal_error<E>(err: E) -> AppError
 where
     E: std::error::Error,
 {
-    (StatusCode::INTERNAL_SERVER_ERROR, err.to_string())
+    AppError::Internal(err.to_string())
 }
-
@@ -0,0 +1,85 @@
+use std::{error::Error, sync::Arc, time::Duration};
+
+use tokio::sync::Mutex;
+
+use crate::{
+    config::FlusherConfig,
+    db::{MemoryDatabase, PostgresDatabase},
+    repository,
+};
+
+// How long an on-demand flush waits for batches other instances are still writing.
+const PEER_FLUSH_WAIT: Duration = Duration::from_millis(500);
+const PEER_FLUSH_POLL: Duration = Duration::from_millis(10);
+
+/// Moves processed payments from the memory database into Postgres.
+/// Each batch is claimed, inserted and only then deleted, so nothing is lost if a step fails.
+#[derive(Debug, Clone)]
+pub struct PaymentFlusher {
+    memory_database: MemoryDatabase,
+    database: PostgresDatabase,
+    batch_size: usize,
+    interval: Duration,
+    lock: Arc<Mutex<()>>,
+}
+
+impl PaymentFlusher {
+    pub fn new(
+        memory_database: MemoryDatabase,
+        database: PostgresDatabase,
+        config: &FlusherConfig,
+    ) -> Self {
+        Self {
+            memory_database,
+            database,
+            batch_size: config.batch_size,
+            interval: Duration::from_millis(config.interval_ms),
+            lock: Arc::new(Mutex::new(())),
+        }
+    }
+
+    /// Flushes until the memory database is empty. Returns how many rows were written.
+    pub async fn flush(&self) -> Result<u64, Box<dyn Error + Send + Sync>> {
+        let _guard = self.lock.lock().await;
+
+        let mut rows_written = 0;
+        loop {
+            let batch = self.memory_database.claim_batch(self.batch_size).await?;
+            if batch.is_empty() {
+                return Ok(rows_written);
+            }
+
+            let (payments, rejected) = repository::parse_memory_entries(&batch);
+            rows_written += repository::insert_memory_payments(&self.database, &payments).await?;
+            self.memory_database.ack_batch(&rejected).await?;
+        }
+    }
+
+    /// Flushes this instance's buffer and gives other instances a moment to finish theirs,
+    /// so a summary read right after sees every processed payment.
+    pub async fn flush_for_read(&self) -> Result<u64, Box<dyn Error + Send + Sync>> {
+        let rows_written = self.flush().await?;
+
+        let deadline = tokio::time::Instant::now() + PEER_FLUSH_WAIT;
+        while self.memory_database.batches_in_flight().await? > 0
+            && tokio::time::Instant::now() < deadline
+        {
+            tokio::time::sleep(PEER_FLUSH_POLL).await;
+        }
+        Ok(rows_written)
+    }
+
+    /// Flushes on every interval tick, or earlier when the buffer crosses its size threshold.
+    pub async fn run(&self) {
+        loop {
+            tokio::select! {
+                _ = tokio::time::sleep(self.interval) => {}
+                _ = self.memory_database.flush_requested() => {}
+            }
+
+            if let Err(e) = self.flush().await {
+                eprintln!("Failed to flush payments: {e:?}");
+            }
+        }
+    }
+}
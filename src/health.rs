@@ -0,0 +1,224 @@
+use std::{
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use chrono::Utc;
+use tokio::{
+    join,
+    sync::{RwLock, RwLockReadGuard},
+    time::Instant,
+};
+
+use crate::{
+    payment_processors::{
+        service::get_service_health,
+        structs::{HealthSnapshot, PaymentProcessorHealth},
+    },
+    storage::LeaderLease,
+    structs::AppState,
+};
+
+/// The latest processor health snapshot this instance knows of, and when it arrived.
+#[derive(Debug)]
+pub struct ProcessorHealthState {
+    snapshot: RwLock<HealthSnapshot>,
+    updated_at: Mutex<Instant>,
+}
+
+impl Default for ProcessorHealthState {
+    fn default() -> Self {
+        Self {
+            snapshot: RwLock::new(HealthSnapshot::unknown()),
+            // Startup counts as fresh, so followers give the leader a chance to publish first.
+            updated_at: Mutex::new(Instant::now()),
+        }
+    }
+}
+
+impl ProcessorHealthState {
+    pub async fn read(&self) -> RwLockReadGuard<'_, HealthSnapshot> {
+        self.snapshot.read().await
+    }
+
+    /// Replaces the current snapshot unless `snapshot` is not newer than it.
+    /// Returns whether it was applied.
+    pub async fn apply(&self, snapshot: HealthSnapshot) -> bool {
+        let mut current = self.snapshot.write().await;
+        if snapshot.sequence <= current.sequence {
+            // The poller sees its own snapshots twice: once applied, once from the bus.
+            if snapshot.sequence < current.sequence {
+                eprintln!(
+                    "Ignoring health snapshot {} from {}, already at {}",
+                    snapshot.sequence, snapshot.source, current.sequence
+                );
+            }
+            return false;
+        }
+        *current = snapshot;
+        *self.updated_at.lock().unwrap_or_else(|e| e.into_inner()) = Instant::now();
+        true
+    }
+
+    /// How long since the last snapshot was applied.
+    pub fn age(&self) -> Duration {
+        self.updated_at
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .elapsed()
+    }
+}
+
+/// Polls both processors' health endpoints. A processor that rate-limits the check keeps
+/// its `last_known` health.
+pub async fn poll_processors(
+    state: &AppState,
+    last_known: &PaymentProcessorHealth,
+) -> PaymentProcessorHealth {
+    let processors = &state.config.processors;
+    let timeout = Duration::from_millis(state.config.health_check.request_timeout_ms);
+    let (default, fallback) = join!(
+        get_service_health(&state.http_client, &processors.default_url, timeout),
+        get_service_health(&state.http_client, &processors.fallback_url, timeout),
+    );
+    PaymentProcessorHealth {
+        default: default.unwrap_or(last_known.default),
+        fallback: fallback.unwrap_or(last_known.fallback),
+    }
+}
+
+/// Applies the cached snapshot if some instance checked the processors within
+/// `poll_interval`, and otherwise polls them and publishes the result.
+/// Returns when the snapshot now in use was taken.
+async fn refresh(state: &AppState, poll_interval: Duration) -> Instant {
+    let cached = state.health_bus.latest().await.unwrap_or_else(|e| {
+        eprintln!("Failed to read the cached health check: {e:?}");
+        None
+    });
+
+    if let Some(snapshot) = cached.as_ref()
+        && snapshot.age() < poll_interval
+    {
+        let taken_at = Instant::now()
+            .checked_sub(snapshot.age())
+            .unwrap_or_else(Instant::now);
+        state.processor_health.apply(snapshot.clone()).await;
+        return taken_at;
+    }
+
+    // Whichever is further along, in case the cache was lost.
+    let current = state.processor_health.read().await.clone();
+    let last = match cached {
+        Some(cached) if cached.sequence > current.sequence => cached,
+        _ => current,
+    };
+    let taken_at = Instant::now();
+    let snapshot = HealthSnapshot {
+        health: poll_processors(state, &last.health).await,
+        observed_at: Utc::now(),
+        source: state.config.hostname.clone(),
+        sequence: last.sequence + 1,
+        observed: state
+            .payment_router
+            .latency
+            .observed(state.payment_router.max_health_age),
+    };
+    if let Err(e) = state.health_bus.publish(&snapshot).await {
+        eprintln!("Failed to publish health check: {e:?}");
+    }
+    state.processor_health.apply(snapshot).await;
+    taken_at
+}
+
+/// Runs on every instance. Whoever holds the lease polls the processors and publishes the
+/// snapshot; the others listen on the health bus, and take the lease over from its holder
+/// when snapshots stop arriving.
+pub async fn run_poller(state: Arc<AppState>, lease: Arc<dyn LeaderLease>) {
+    let config = &state.config.health_check;
+    let holder = state.config.hostname.as_str();
+    let poll_interval = Duration::from_millis(config.poll_interval_ms);
+    let stale_after = Duration::from_millis(config.stale_after_ms);
+
+    let mut leading = false;
+    let mut last_poll: Option<Instant> = None;
+    loop {
+        let acquired = if !leading && state.processor_health.age() > stale_after {
+            match lease.holder().await {
+                Ok(Some(stale)) if stale != holder => {
+                    eprintln!("Health snapshot is stale, taking health checks over from {stale}");
+                    lease.take_over(holder, &stale).await
+                }
+                Ok(_) => lease.acquire(holder).await,
+                Err(e) => Err(e),
+            }
+        } else {
+            lease.acquire(holder).await
+        };
+
+        match acquired {
+            Ok(true) if !leading => {
+                println!("Leading health checks as {holder}");
+                leading = true;
+            }
+            Ok(false) if leading => {
+                println!("Lost the health check lease");
+                leading = false;
+                last_poll = None;
+            }
+            Ok(_) => {}
+            Err(e) => {
+                eprintln!("Failed to renew the health check lease: {e:?}");
+                leading = false;
+                last_poll = None;
+            }
+        }
+
+        if leading && last_poll.is_none_or(|at| at.elapsed() >= poll_interval) {
+            last_poll = Some(refresh(&state, poll_interval).await);
+        }
+
+        tokio::time::sleep(Duration::from_millis(config.lease_renew_interval_ms)).await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::payment_processors::structs::PaymentProcessorHealthCheckDTO;
+
+    fn snapshot(sequence: u64, age_ms: i64, default_failing: bool) -> HealthSnapshot {
+        let check = |failing| PaymentProcessorHealthCheckDTO {
+            failing,
+            min_response_time: 50,
+        };
+        HealthSnapshot {
+            health: PaymentProcessorHealth {
+                default: check(default_failing),
+                fallback: check(false),
+            },
+            observed_at: Utc::now() - chrono::Duration::milliseconds(age_ms),
+            source: "api1".to_string(),
+            sequence,
+            observed: Default::default(),
+        }
+    }
+
+    #[tokio::test]
+    async fn drops_snapshots_that_arrive_out_of_order() {
+        let state = ProcessorHealthState::default();
+        assert!(state.apply(snapshot(2, 0, true)).await);
+        assert!(!state.apply(snapshot(1, 0, false)).await);
+        assert!(!state.apply(snapshot(2, 0, false)).await);
+        assert!(state.read().await.health.default.failing);
+        assert!(state.apply(snapshot(3, 0, false)).await);
+        assert!(!state.read().await.health.default.failing);
+    }
+
+    #[test]
+    fn routes_stale_snapshots_as_unknown() {
+        let max_age = Duration::from_secs(20);
+        assert!(snapshot(1, 1_000, true).routable(max_age).default.failing);
+        assert!(!snapshot(1, 30_000, true).routable(max_age).default.failing);
+        assert!(!HealthSnapshot::unknown().routable(max_age).default.failing);
+    }
+}
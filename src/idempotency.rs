@@ -0,0 +1,88 @@
+use bb8::Pool;
+use bb8_redis::RedisConnectionManager;
+use bb8_redis::redis::{AsyncCommands, ExistenceCheck, SetExpiry, SetOptions};
+use uuid::Uuid;
+
+use crate::config::IdempotencyConfig;
+
+const PURGE_BATCH_SIZE: usize = 1_000;
+
+pub(crate) type IdempotencyConnection = Pool<RedisConnectionManager>;
+
+#[derive(Debug, Clone)]
+pub struct IdempotencyStore {
+    pool: IdempotencyConnection,
+    key_prefix: String,
+    ttl_seconds: u64,
+}
+
+impl IdempotencyStore {
+    pub fn new(pool: IdempotencyConnection, config: &IdempotencyConfig) -> Self {
+        Self {
+            pool,
+            key_prefix: config.key_prefix.clone(),
+            ttl_seconds: config.ttl_seconds,
+        }
+    }
+
+    /// Atomically claims `correlation_id`. Returns `false` when another request already did.
+    pub async fn claim(&self, correlation_id: Uuid) -> Result<bool, bb8_redis::redis::RedisError> {
+        let mut conn = self.pool.get().await.map_err(|e| {
+            bb8_redis::redis::RedisError::from((
+                bb8_redis::redis::ErrorKind::IoError,
+                "bb8 pool error",
+                e.to_string(),
+            ))
+        })?;
+
+        let options = SetOptions::default()
+            .conditional_set(ExistenceCheck::NX)
+            .with_expiration(SetExpiry::EX(self.ttl_seconds));
+        let claimed: Option<String> =
+            AsyncCommands::set_options(&mut *conn, self.key(correlation_id), 1, options).await?;
+
+        Ok(claimed.is_some())
+    }
+
+    /// Drops the claim on `correlation_id`, so the payment can be sent again.
+    pub async fn release(&self, correlation_id: Uuid) -> Result<(), bb8_redis::redis::RedisError> {
+        let mut conn = self.pool.get().await.map_err(|e| {
+            bb8_redis::redis::RedisError::from((
+                bb8_redis::redis::ErrorKind::IoError,
+                "bb8 pool error",
+                e.to_string(),
+            ))
+        })?;
+
+        let _: () = AsyncCommands::del(&mut *conn, self.key(correlation_id)).await?;
+        Ok(())
+    }
+
+    /// Drops every claim, so any payment can be sent again.
+    pub async fn purge(&self) -> Result<(), bb8_redis::redis::RedisError> {
+        let mut conn = self.pool.get().await.map_err(|e| {
+            bb8_redis::redis::RedisError::from((
+                bb8_redis::redis::ErrorKind::IoError,
+                "bb8 pool error",
+                e.to_string(),
+            ))
+        })?;
+
+        let pattern = format!("{}:*", self.key_prefix);
+        let mut keys: Vec<String> = Vec::new();
+        {
+            let mut iter = AsyncCommands::scan_match::<_, String>(&mut *conn, &pattern).await?;
+            while let Some(key) = iter.next_item().await {
+                keys.push(key);
+            }
+        }
+        for chunk in keys.chunks(PURGE_BATCH_SIZE) {
+            let _: () = AsyncCommands::del(&mut *conn, chunk).await?;
+        }
+        Ok(())
+    }
+
+    fn key(&self, correlation_id: Uuid) -> String {
+        format!("{}:{}", self.key_prefix, correlation_id)
+    }
+}
@@ -0,0 +1,124 @@
+use std::sync::LazyLock;
+
+use bb8::{Pool, PooledConnection};
+use bb8_redis::RedisConnectionManager;
+use redis::{AsyncCommands, Script};
+
+use crate::config::HealthCheckConfig;
+
+pub(crate) type LeaderLeaseConnection = Pool<RedisConnectionManager>;
+
+// Extends the lease when ARGV[1] holds it, or takes it when nobody does.
+static ACQUIRE_SCRIPT: LazyLock<Script> = LazyLock::new(|| {
+    Script::new(
+        r"
+        if redis.call('GET', KEYS[1]) == ARGV[1] then
+            redis.call('PEXPIRE', KEYS[1], ARGV[2])
+            return 1
+        end
+        if redis.call('SET', KEYS[1], ARGV[1], 'NX', 'PX', ARGV[2]) then
+            return 1
+        end
+        return 0
+        ",
+    )
+});
+
+// Hands the lease to ARGV[1] only if ARGV[2] still holds it, so of several instances taking it
+// over from the same stale holder only the first gets it.
+static TAKE_OVER_SCRIPT: LazyLock<Script> = LazyLock::new(|| {
+    Script::new(
+        r"
+        if redis.call('GET', KEYS[1]) == ARGV[2] then
+            redis.call('SET', KEYS[1], ARGV[1], 'PX', ARGV[3])
+            return 1
+        end
+        return 0
+        ",
+    )
+});
+
+// Deletes the lease only if ARGV[1] still holds it.
+static RELEASE_SCRIPT: LazyLock<Script> = LazyLock::new(|| {
+    Script::new(
+        r"
+        if redis.call('GET', KEYS[1]) == ARGV[1] then
+            return redis.call('DEL', KEYS[1])
+        end
+        return 0
+        ",
+    )
+});
+
+/// A lease key in Redis naming the instance that polls processor health. The holder renews it
+/// well within its TTL, so it expires shortly after the holder goes away.
+#[derive(Debug, Clone)]
+pub struct RedisLeaderLease {
+    pool: LeaderLeaseConnection,
+    key: String,
+    ttl_ms: u64,
+}
+
+impl RedisLeaderLease {
+    pub fn new(pool: LeaderLeaseConnection, config: &HealthCheckConfig) -> Self {
+        Self {
+            pool,
+            key: config.lease_key.clone(),
+            ttl_ms: config.lease_ttl_ms,
+        }
+    }
+
+    pub async fn acquire(&self, holder: &str) -> Result<bool, bb8_redis::redis::RedisError> {
+        let mut conn = self.connection().await?;
+        let acquired: i32 = ACQUIRE_SCRIPT
+            .key(&self.key)
+            .arg(holder)
+            .arg(self.ttl_ms)
+            .invoke_async(&mut *conn)
+            .await?;
+        Ok(acquired == 1)
+    }
+
+    pub async fn holder(&self) -> Result<Option<String>, bb8_redis::redis::RedisError> {
+        let mut conn = self.connection().await?;
+        AsyncCommands::get(&mut *conn, &self.key).await
+    }
+
+    pub async fn take_over(
+        &self,
+        holder: &str,
+        stale: &str,
+    ) -> Result<bool, bb8_redis::redis::RedisError> {
+        let mut conn = self.connection().await?;
+        let taken: i32 = TAKE_OVER_SCRIPT
+            .key(&self.key)
+            .arg(holder)
+            .arg(stale)
+            .arg(self.ttl_ms)
+            .invoke_async(&mut *conn)
+            .await?;
+        Ok(taken == 1)
+    }
+
+    pub async fn release(&self, holder: &str) -> Result<(), bb8_redis::redis::RedisError> {
+        let mut conn = self.connection().await?;
+        let _: i32 = RELEASE_SCRIPT
+            .key(&self.key)
+            .arg(holder)
+            .invoke_async(&mut *conn)
+            .await?;
+        Ok(())
+    }
+
+    async fn connection(
+        &self,
+    ) -> Result<PooledConnection<'_, RedisConnectionManager>, bb8_redis::redis::RedisError> {
+        self.pool.get().await.map_err(|e| {
+            bb8_redis::redis::RedisError::from((
+                bb8_redis::redis::ErrorKind::IoError,
+                "bb8 pool error",
+                e.to_string(),
+            ))
+        })
+    }
+}
@@ -0,0 +1,117 @@
+use std::sync::Mutex;
+
+use async_trait::async_trait;
+use chrono::{DateTime, Utc};
+use tokio::net::UnixStream;
+use uuid::Uuid;
+
+use crate::{
+    config::StoreConfig,
+    ledger::protocol::{Request, Response, read_frame, write_frame},
+    storage::{PaymentStore, StorageError},
+    structs::{PaymentDatabaseEntry, PaymentsSummaryResponseDTO},
+};
+
+/// Payments kept by a store process, reached over its Unix socket.
+/// Connections are reused; one that fails mid-request is dropped rather than returned.
+/// A request that fails on a reused connection is sent again once on a new one, since idle
+/// connections go stale when the store restarts.
+#[derive(Debug)]
+pub struct SocketPaymentStore {
+    path: String,
+    idle: Mutex<Vec<UnixStream>>,
+    max_idle: usize,
+}
+
+impl SocketPaymentStore {
+    pub fn new(config: &StoreConfig) -> Self {
+        Self {
+            path: config.socket_path.clone(),
+            idle: Mutex::new(Vec::new()),
+            max_idle: config.max_idle_connections,
+        }
+    }
+
+    async fn call(&self, request: Request) -> Result<Response, StorageError> {
+        let mut frame = Vec::new();
+        request.encode(&mut frame);
+
+        let idle = self.idle.lock().map_err(|e| e.to_string())?.pop();
+        let (stream, buf) = match idle {
+            Some(stream) => match exchange(stream, &frame).await {
+                Ok(exchanged) => exchanged,
+                Err(_) => exchange(UnixStream::connect(&self.path).await?, &frame).await?,
+            },
+            None => exchange(UnixStream::connect(&self.path).await?, &frame).await?,
+        };
+        let response = Response::decode(&buf)?;
+
+        let mut idle = self.idle.lock().map_err(|e| e.to_string())?;
+        if idle.len() < self.max_idle {
+            idle.push(stream);
+        }
+        match response {
+            Response::Error(message) => Err(message.into()),
+            response => Ok(response),
+        }
+    }
+}
+
+/// Sends one request frame over `stream` and reads the reply frame.
+async fn exchange(
+    mut stream: UnixStream,
+    frame: &[u8],
+) -> Result<(UnixStream, Vec<u8>), StorageError> {
+    write_frame(&mut stream, frame).await?;
+    let mut buf = Vec::new();
+    if !read_frame(&mut stream, &mut buf).await? {
+        return Err("store closed the connection".into());
+    }
+    Ok((stream, buf))
+}
+
+fn unexpected(response: Response) -> StorageError {
+    format!("unexpected response from store: {response:?}").into()
+}
+
+#[async_trait]
+impl PaymentStore for SocketPaymentStore {
+    async fn claim(&self, correlation_id: Uuid) -> Result<bool, StorageError> {
+        match self.call(Request::Claim(correlation_id)).await? {
+            Response::Claimed(claimed) => Ok(claimed),
+            response => Err(unexpected(response)),
+        }
+    }
+
+    async fn release(&self, correlation_id: Uuid) -> Result<(), StorageError> {
+        match self.call(Request::Release(correlation_id)).await? {
+            Response::Released => Ok(()),
+            response => Err(unexpected(response)),
+        }
+    }
+
+    async fn record(&self, entry: &PaymentDatabaseEntry) -> Result<(), StorageError> {
+        match self.call(Request::Append(entry.clone())).await? {
+            Response::Appended => Ok(()),
+            response => Err(unexpected(response)),
+        }
+    }
+
+    async fn summary(
+        &self,
+        from: Option<DateTime<Utc>>,
+        to: Option<DateTime<Utc>>,
+    ) -> Result<PaymentsSummaryResponseDTO, StorageError> {
+        match self.call(Request::Summary { from, to }).await? {
+            Response::Summarized(summary) => Ok(summary),
+            response => Err(unexpected(response)),
+        }
+    }
+
+    async fn purge(&self) -> Result<u64, StorageError> {
+        match self.call(Request::Purge).await? {
+            Response::Purged(purged) => Ok(purged),
+            response => Err(unexpected(response)),
+        }
+    }
+}
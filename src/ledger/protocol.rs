@@ -0,0 +1,343 @@
+//! Frames exchanged between API instances and the store over its Unix socket.
+//!
+//! Every frame is a big-endian `u32` body length followed by the body. A body starts with a
+//! one-byte tag and carries fixed-width fields: UUIDs as 16 bytes, timestamps as `i64`
+//! microseconds since the epoch, amounts as `i64` cents.
+
+use std::io;
+
+use chrono::{DateTime, Utc};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use uuid::Uuid;
+
+use crate::{
+    money::Money,
+    payment_processors::service::PaymentProcessorServices,
+    structs::{PaymentDatabaseEntry, PaymentsServiceSummary, PaymentsSummaryResponseDTO},
+};
+
+// Bodies are a few dozen bytes; anything near this is a corrupt stream.
+const MAX_FRAME_LEN: u32 = 64 * 1024;
+
+const TAG_CLAIM: u8 = 0x01;
+const TAG_APPEND: u8 = 0x02;
+const TAG_SUMMARY: u8 = 0x03;
+const TAG_PURGE: u8 = 0x04;
+const TAG_RELEASE: u8 = 0x05;
+
+const TAG_CLAIMED: u8 = 0x81;
+const TAG_APPENDED: u8 = 0x82;
+const TAG_SUMMARIZED: u8 = 0x83;
+const TAG_PURGED: u8 = 0x84;
+const TAG_RELEASED: u8 = 0x85;
+const TAG_ERROR: u8 = 0xff;
+
+const HAS_FROM: u8 = 0b01;
+const HAS_TO: u8 = 0b10;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Request {
+    Claim(Uuid),
+    Append(PaymentDatabaseEntry),
+    Summary {
+        from: Option<DateTime<Utc>>,
+        to: Option<DateTime<Utc>>,
+    },
+    Purge,
+    Release(Uuid),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Response {
+    Claimed(bool),
+    Appended,
+    Summarized(PaymentsSummaryResponseDTO),
+    Purged(u64),
+    Released,
+    Error(String),
+}
+
+impl Request {
+    pub fn encode(&self, buf: &mut Vec<u8>) {
+        buf.clear();
+        match self {
+            Request::Claim(correlation_id) => {
+                buf.push(TAG_CLAIM);
+                buf.extend_from_slice(correlation_id.as_bytes());
+            }
+            Request::Append(entry) => {
+                buf.push(TAG_APPEND);
+                buf.extend_from_slice(entry.correlation_id.as_bytes());
+                buf.extend_from_slice(&entry.requested_at.timestamp_micros().to_be_bytes());
+                buf.extend_from_slice(&entry.amount.cents().to_be_bytes());
+                buf.push(match entry.service {
+                    PaymentProcessorServices::Default => 0,
+                    PaymentProcessorServices::Fallback => 1,
+                });
+            }
+            Request::Summary { from, to } => {
+                buf.push(TAG_SUMMARY);
+                let flags = if from.is_some() { HAS_FROM } else { 0 }
+                    | if to.is_some() { HAS_TO } else { 0 };
+                buf.push(flags);
+                for bound in [from, to] {
+                    let micros = bound.map_or(0, |bound| bound.timestamp_micros());
+                    buf.extend_from_slice(&micros.to_be_bytes());
+                }
+            }
+            Request::Purge => buf.push(TAG_PURGE),
+            Request::Release(correlation_id) => {
+                buf.push(TAG_RELEASE);
+                buf.extend_from_slice(correlation_id.as_bytes());
+            }
+        }
+    }
+
+    pub fn decode(body: &[u8]) -> io::Result<Self> {
+        let mut reader = Reader::new(body);
+        let request = match reader.u8()? {
+            TAG_CLAIM => Request::Claim(reader.uuid()?),
+            TAG_APPEND => Request::Append(PaymentDatabaseEntry {
+                correlation_id: reader.uuid()?,
+                requested_at: reader.timestamp()?,
+                amount: Money::from_cents(reader.i64()?),
+                service: match reader.u8()? {
+                    0 => PaymentProcessorServices::Default,
+                    1 => PaymentProcessorServices::Fallback,
+                    other => return Err(invalid(format!("unknown service {other}"))),
+                },
+            }),
+            TAG_SUMMARY => {
+                let flags = reader.u8()?;
+                let from = reader.timestamp()?;
+                let to = reader.timestamp()?;
+                Request::Summary {
+                    from: (flags & HAS_FROM != 0).then_some(from),
+                    to: (flags & HAS_TO != 0).then_some(to),
+                }
+            }
+            TAG_PURGE => Request::Purge,
+            TAG_RELEASE => Request::Release(reader.uuid()?),
+            other => return Err(invalid(format!("unknown request tag {other:#04x}"))),
+        };
+        reader.finish()?;
+        Ok(request)
+    }
+}
+
+impl Response {
+    pub fn encode(&self, buf: &mut Vec<u8>) {
+        buf.clear();
+        match self {
+            Response::Claimed(claimed) => {
+                buf.push(TAG_CLAIMED);
+                buf.push(u8::from(*claimed));
+            }
+            Response::Appended => buf.push(TAG_APPENDED),
+            Response::Summarized(summary) => {
+                buf.push(TAG_SUMMARIZED);
+                for service_summary in [&summary.default, &summary.fallback] {
+                    buf.extend_from_slice(&service_summary.total_requests.to_be_bytes());
+                    buf.extend_from_slice(&service_summary.total_amount.cents().to_be_bytes());
+                }
+            }
+            Response::Purged(purged) => {
+                buf.push(TAG_PURGED);
+                buf.extend_from_slice(&purged.to_be_bytes());
+            }
+            Response::Released => buf.push(TAG_RELEASED),
+            Response::Error(message) => {
+                buf.push(TAG_ERROR);
+                buf.extend_from_slice(message.as_bytes());
+            }
+        }
+    }
+
+    pub fn decode(body: &[u8]) -> io::Result<Self> {
+        let mut reader = Reader::new(body);
+        let response = match reader.u8()? {
+            TAG_CLAIMED => Response::Claimed(reader.u8()? != 0),
+            TAG_APPENDED => Response::Appended,
+            TAG_SUMMARIZED => {
+                let mut service_summary = || -> io::Result<PaymentsServiceSummary> {
+                    Ok(PaymentsServiceSummary {
+                        total_requests: reader.u32()?,
+                        total_amount: Money::from_cents(reader.i64()?),
+                    })
+                };
+                let default = service_summary()?;
+                let fallback = service_summary()?;
+                Response::Summarized(PaymentsSummaryResponseDTO { default, fallback })
+            }
+            TAG_PURGED => Response::Purged(reader.u64()?),
+            TAG_RELEASED => Response::Released,
+            TAG_ERROR => Response::Error(String::from_utf8_lossy(reader.rest()).into_owned()),
+            other => return Err(invalid(format!("unknown response tag {other:#04x}"))),
+        };
+        reader.finish()?;
+        Ok(response)
+    }
+}
+
+/// Reads the next frame body into `buf`. Returns `false` when the peer closed the stream
+/// between frames.
+pub async fn read_frame<R: AsyncRead + Unpin>(
+    reader: &mut R,
+    buf: &mut Vec<u8>,
+) -> io::Result<bool> {
+    let len = match reader.read_u32().await {
+        Ok(len) => len,
+        Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => return Ok(false),
+        Err(e) => return Err(e),
+    };
+    if len > MAX_FRAME_LEN {
+        return Err(invalid(format!("frame of {len} bytes is too large")));
+    }
+
+    buf.resize(len as usize, 0);
+    reader.read_exact(buf).await?;
+    Ok(true)
+}
+
+pub async fn write_frame<W: AsyncWrite + Unpin>(writer: &mut W, body: &[u8]) -> io::Result<()> {
+    writer.write_u32(body.len() as u32).await?;
+    writer.write_all(body).await?;
+    writer.flush().await
+}
+
+fn invalid(message: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+struct Reader<'a> {
+    body: &'a [u8],
+}
+
+impl<'a> Reader<'a> {
+    fn new(body: &'a [u8]) -> Self {
+        Self { body }
+    }
+
+    fn take<const N: usize>(&mut self) -> io::Result<[u8; N]> {
+        let Some((head, rest)) = self.body.split_first_chunk::<N>() else {
+            return Err(invalid("frame ended early".to_string()));
+        };
+        self.body = rest;
+        Ok(*head)
+    }
+
+    fn u8(&mut self) -> io::Result<u8> {
+        Ok(self.take::<1>()?[0])
+    }
+
+    fn u32(&mut self) -> io::Result<u32> {
+        Ok(u32::from_be_bytes(self.take()?))
+    }
+
+    fn u64(&mut self) -> io::Result<u64> {
+        Ok(u64::from_be_bytes(self.take()?))
+    }
+
+    fn i64(&mut self) -> io::Result<i64> {
+        Ok(i64::from_be_bytes(self.take()?))
+    }
+
+    fn uuid(&mut self) -> io::Result<Uuid> {
+        Ok(Uuid::from_bytes(self.take()?))
+    }
+
+    fn timestamp(&mut self) -> io::Result<DateTime<Utc>> {
+        let micros = self.i64()?;
+        DateTime::from_timestamp_micros(micros)
+            .ok_or_else(|| invalid(format!("timestamp {micros} is out of range")))
+    }
+
+    fn rest(&mut self) -> &'a [u8] {
+        std::mem::take(&mut self.body)
+    }
+
+    fn finish(&self) -> io::Result<()> {
+        if self.body.is_empty() {
+            Ok(())
+        } else {
+            Err(invalid(format!(
+                "{} unexpected trailing bytes",
+                self.body.len()
+            )))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn round_trips_every_frame() {
+        let at: DateTime<Utc> = "2025-07-01T12:00:00.123456Z".parse().unwrap();
+        let summary = PaymentsSummaryResponseDTO {
+            default: PaymentsServiceSummary {
+                total_requests: 3,
+                total_amount: Money::from_cents(5970),
+            },
+            fallback: PaymentsServiceSummary {
+                total_requests: 1,
+                total_amount: Money::from_cents(1990),
+            },
+        };
+
+        let requests = [
+            Request::Claim(Uuid::from_u128(42)),
+            Request::Append(PaymentDatabaseEntry {
+                correlation_id: Uuid::from_u128(42),
+                requested_at: at,
+                amount: Money::from_cents(1990),
+                service: PaymentProcessorServices::Fallback,
+            }),
+            Request::Summary {
+                from: None,
+                to: None,
+            },
+            Request::Summary {
+                from: Some(at),
+                to: None,
+            },
+            Request::Summary {
+                from: None,
+                to: Some(at),
+            },
+            Request::Purge,
+            Request::Release(Uuid::from_u128(42)),
+        ];
+        let mut buf = Vec::new();
+        for request in requests {
+            request.encode(&mut buf);
+            assert_eq!(Request::decode(&buf).unwrap(), request);
+        }
+
+        let responses = [
+            Response::Claimed(true),
+            Response::Claimed(false),
+            Response::Appended,
+            Response::Summarized(summary),
+            Response::Purged(7),
+            Response::Released,
+            Response::Error("boom".to_string()),
+        ];
+        for response in responses {
+            response.encode(&mut buf);
+            assert_eq!(Response::decode(&buf).unwrap(), response);
+        }
+    }
+
+    #[test]
+    fn rejects_malformed_bodies() {
+        let mut buf = Vec::new();
+        Request::Claim(Uuid::from_u128(1)).encode(&mut buf);
+        assert!(Request::decode(&buf[..buf.len() - 1]).is_err());
+        buf.push(0);
+        assert!(Request::decode(&buf).is_err());
+        assert!(Request::decode(&[0x7f]).is_err());
+        assert!(Response::decode(&[]).is_err());
+    }
+}
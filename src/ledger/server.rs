@@ -0,0 +1,149 @@
+use std::{io, sync::Arc, time::Duration};
+
+use tokio::{
+    io::{BufReader, BufWriter},
+    net::{UnixListener, UnixStream},
+};
+
+use crate::{
+    ledger::protocol::{Request, Response, read_frame, write_frame},
+    storage::{PaymentStore, memory::InMemoryPaymentStore},
+};
+
+/// Runs the store role: owns the payments ledger in memory and serves it to the API
+/// instances over a Unix socket at `path`. Claims are kept for `claim_ttl`. Only returns if
+/// the socket can't be used.
+pub async fn run(path: &str, claim_ttl: Duration) -> io::Result<()> {
+    // A socket file left by a previous run would make bind fail.
+    match std::fs::remove_file(path) {
+        Err(e) if e.kind() != io::ErrorKind::NotFound => return Err(e),
+        _ => {}
+    }
+    let listener = UnixListener::bind(path)?;
+    println!("Store listening on {path}");
+
+    let store: Arc<dyn PaymentStore> = Arc::new(InMemoryPaymentStore::new(claim_ttl));
+    loop {
+        let (stream, _) = listener.accept().await?;
+        let store = store.clone();
+        tokio::spawn(async move {
+            if let Err(e) = serve(stream, store.as_ref()).await {
+                eprintln!("Store connection failed: {e:?}");
+            }
+        });
+    }
+}
+
+async fn serve(stream: UnixStream, store: &dyn PaymentStore) -> io::Result<()> {
+    let (reader, writer) = stream.into_split();
+    let mut reader = BufReader::new(reader);
+    let mut writer = BufWriter::new(writer);
+
+    let mut buf = Vec::new();
+    while read_frame(&mut reader, &mut buf).await? {
+        let response = match Request::decode(&buf) {
+            Ok(request) => handle(store, request).await,
+            Err(e) => Response::Error(e.to_string()),
+        };
+        response.encode(&mut buf);
+        write_frame(&mut writer, &buf).await?;
+    }
+    Ok(())
+}
+
+async fn handle(store: &dyn PaymentStore, request: Request) -> Response {
+    let result = match request {
+        Request::Claim(correlation_id) => store.claim(correlation_id).await.map(Response::Claimed),
+        Request::Release(correlation_id) => store
+            .release(correlation_id)
+            .await
+            .map(|()| Response::Released),
+        Request::Append(entry) => store.record(&entry).await.map(|()| Response::Appended),
+        Request::Summary { from, to } => store.summary(from, to).await.map(Response::Summarized),
+        Request::Purge => store.purge().await.map(Response::Purged),
+    };
+    result.unwrap_or_else(|e| Response::Error(e.to_string()))
+}
+
+#[cfg(test)]
+mod tests {
+    use uuid::Uuid;
+
+    use super::*;
+    use crate::{
+        config::StoreConfig, ledger::client::SocketPaymentStore, money::Money,
+        payment_processors::service::PaymentProcessorServices, structs::PaymentDatabaseEntry,
+    };
+
+    #[tokio::test]
+    async fn serves_a_shared_ledger_over_the_socket() {
+        let path = std::env::temp_dir().join(format!("rinha-store-{}.sock", std::process::id()));
+        let path = path.to_str().unwrap().to_string();
+        let server_path = path.clone();
+        tokio::spawn(async move { run(&server_path, Duration::from_secs(60)).await });
+        tokio::time::sleep(Duration::from_millis(50)).await;
+
+        let config = StoreConfig {
+            socket_path: path.clone(),
+            ..StoreConfig::default()
+        };
+        let api01 = SocketPaymentStore::new(&config);
+        let api02 = SocketPaymentStore::new(&config);
+        let id = Uuid::from_u128(9);
+        assert!(api01.claim(id).await.unwrap());
+        assert!(!api02.claim(id).await.unwrap());
+        api02.release(id).await.unwrap();
+        assert!(api01.claim(id).await.unwrap());
+
+        let entry = PaymentDatabaseEntry {
+            correlation_id: id,
+            requested_at: "2025-07-01T12:00:00.250Z".parse().unwrap(),
+            amount: Money::from_cents(1990),
+            service: PaymentProcessorServices::Default,
+        };
+        api01.record(&entry).await.unwrap();
+        api02.record(&entry).await.unwrap();
+
+        let summary = api02.summary(None, None).await.unwrap();
+        assert_eq!(summary.default.total_requests, 1);
+        assert_eq!(summary.default.total_amount, Money::from_cents(1990));
+        let later = "2025-07-01T12:00:00.251Z".parse().ok();
+        let summary = api01.summary(later, None).await.unwrap();
+        assert_eq!(summary.default.total_requests, 0);
+
+        assert_eq!(api02.purge().await.unwrap(), 1);
+        let _ = std::fs::remove_file(&path);
+    }
+
+    #[tokio::test]
+    async fn resends_a_request_when_a_reused_connection_went_stale() {
+        let path =
+            std::env::temp_dir().join(format!("rinha-store-stale-{}.sock", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let listener = UnixListener::bind(&path).unwrap();
+        tokio::spawn(async move {
+            let store = InMemoryPaymentStore::default();
+            // The first connection answers once and closes, as if the store restarted.
+            let (mut first, _) = listener.accept().await.unwrap();
+            let mut buf = Vec::new();
+            read_frame(&mut first, &mut buf).await.unwrap();
+            handle(&store, Request::decode(&buf).unwrap())
+                .await
+                .encode(&mut buf);
+            write_frame(&mut first, &buf).await.unwrap();
+            drop(first);
+
+            let (second, _) = listener.accept().await.unwrap();
+            serve(second, &store).await.unwrap();
+        });
+
+        let store = SocketPaymentStore::new(&StoreConfig {
+            socket_path: path.to_str().unwrap().to_string(),
+            ..StoreConfig::default()
+        });
+        assert!(store.claim(Uuid::from_u128(1)).await.unwrap());
+        assert!(store.claim(Uuid::from_u128(2)).await.unwrap());
+        assert!(!store.claim(Uuid::from_u128(1)).await.unwrap());
+        let _ = std::fs::remove_file(&path);
+    }
+}
@@ -1,28 +1,58 @@
-use std::{env, sync::Arc, time::Duration};
-use tokio::{join, sync::RwLock};
+use std::{sync::Arc, time::Duration};
 
 use crate::{
-    db::{DEFAULT_DATABASE_URL, DEFAULT_MEMORY_DATABASE_URL, MemoryDatabaseConnection},
+    config::{Config, Role},
     structs::AppState,
 };
-use bb8::Pool;
-use bb8_postgres::PostgresConnectionManager;
-use bb8_redis::RedisConnectionManager;
-use tokio_postgres::NoTls;
 use tower::limit::ConcurrencyLimitLayer;
-// use crate::payment_processors;
+mod admission;
+mod config;
 mod controller;
 mod db;
+mod dead_letter;
 mod error_handling;
+mod flusher;
+mod health;
+mod idempotency;
+mod leader;
+mod ledger;
+mod money;
 pub mod payment_processors;
 mod queue;
+mod reconciliation;
 mod pubsub;
 mod repository;
+mod retry;
 mod service;
+mod shutdown;
+mod storage;
 mod structs;
+mod summary;
+#[cfg(test)]
+mod test_support;
+mod validation;
+mod worker;
 
 #[tokio::main]
 async fn main() {
+    let config = match Config::load() {
+        Ok(config) => config,
+        Err(e) => {
+            eprintln!("Invalid configuration: {e}");
+            std::process::exit(2);
+        }
+    };
+    println!("Effective configuration:\n{config}");
+
+    if config.role == Role::Store {
+        println!("Starting the payments store...");
+        let claim_ttl = Duration::from_secs(config.idempotency.ttl_seconds);
+        if let Err(e) = ledger::server::run(&config.store.socket_path, claim_ttl).await {
+            eprintln!("Store stopped: {e:?}");
+        }
+        return;
+    }
+
     println!("Starting the payment processing server...");
 
     let http_client = reqwest::Client::builder()
@@ -33,74 +63,31 @@ async fn main() {
         .build()
         .unwrap();
 
-
-    // Constants
-    let database_url =
-        std::env::var("DATABASE_URL").unwrap_or_else(|_| DEFAULT_DATABASE_URL.to_string());
-    let memory_database_url = std::env::var("MEMORY_DATABASE_URL")
-        .unwrap_or_else(|_| DEFAULT_MEMORY_DATABASE_URL.to_string());
-    let num_workers = env::var("NUM_WORKERS")
-        .unwrap_or_else(|_| "50".to_string())
-        .parse::<usize>()
-        .unwrap_or(50);
-    let instance = std::env::var("INSTANCE").unwrap_or_else(|_| "".to_string());
-        
-
-
-    println!("Starting Postgres Connection Pool")
-    let manager = PostgresConnectionManager::new_from_stringlike(database_url, NoTls).unwrap();
-    let pool: Pool<PostgresConnectionManager<NoTls>> =
-    bb8::Pool::builder().build(manager).await.unwrap();
-    let database = db::PostgresDatabase::new(pool);
-
-    
-    println!("Starting Redis Connection Pool")
-    let memory_manager = RedisConnectionManager::new(memory_database_url).unwrap();     
-    let memory_pool: MemoryDatabaseConnection = bb8::Pool::builder()
-        .min_idle(10)
-        .max_size(32)
-        .build(memory_manager)
-        .await
-        .unwrap();
-    let memory_database = db::MemoryDatabase::new(memory_pool.clone());
-    
-    println!("Starting Channel")
-    let health_check_channel = pubsub::HealthCheckChannel::new(memory_pool.clone());
-
-    println!("Starting DLQ")
-    let redis_queue = queue::RedisQueue::new(memory_pool);
-
-
-    
-
-    
+    println!("Connecting {:?} storage", config.storage.backend);
+    let storage = storage::connect(&config).await;
 
     println!("Creating App State...");
 
-    let processor_health = Arc::new(RwLock::new(
-        payment_processors::structs::PaymentProcessorHealth {
-            default: payment_processors::structs::PaymentProcessorHealthCheckDTO {
-                failing: false,
-                min_response_time: 0,
-            },
-            fallback: payment_processors::structs::PaymentProcessorHealthCheckDTO {
-                failing: false,
-                min_response_time: 0,
-            },
-        },
-    ));
-
     
-    let health_check_http_client = http_client.clone();
-    let processor_health_clone = processor_health.clone();
+    let payment_router = payment_processors::routing::PaymentRouter::from_config(
+        &config.routing,
+        &config.processors,
+        storage.circuit_breaker.clone(),
+    );
 
+    let config = Arc::new(config);
     let app_state = Arc::new(AppState {
-        database,
-        memory_database,
+        config: config.clone(),
+        payments: storage.payments.clone(),
+        queue: storage.queue.clone(),
+        dead_letters: storage.dead_letters.clone(),
+        health_bus: storage.health_bus.clone(),
         http_client,
-        redis_queue,
-        processor_health,
-        health_check_channel,
+        payment_router,
+        processor_health: Arc::new(health::ProcessorHealthState::default()),
+        shutdown: shutdown::Shutdown::default(),
+        in_flight: Arc::new(shutdown::InFlightPayments::default()),
+        admission: Arc::new(admission::AdmissionQueue::new(config.admission.capacity)),
     });
 
 
@@ -109,123 +96,87 @@ async fn main() {
 
 
 
-    let app_state_clone = app_state.clone();
-    if instance == "MASTER" {
-        println!("Starting health check thread");
-        tokio::spawn(async move {
-            loop {
-                
-
-
-                let (default, fallback) = join!(
-                    payment_processors::service::get_service_health(
-                        &health_check_http_client,
-                        payment_processors::service::PaymentProcessorServices::Default
-                    ),
-                    payment_processors::service::get_service_health(
-                        &health_check_http_client,
-                        payment_processors::service::PaymentProcessorServices::Fallback
-                    )
-                );
-
-                // Example: let health = payment_processors::service::check_health().await;
-                let health =
-                payment_processors::structs::PaymentProcessorHealth { default, fallback };
-
-                let channel = &app_state_clone.health_check_channel.update(health.clone());
+    println!("Starting health check subscriber");
+    let subscriber_state = app_state.clone();
+    tokio::spawn(async move {
+        subscriber_state
+            .health_bus
+            .listen(subscriber_state.processor_health.clone())
+            .await;
+    });
 
-                let mut guard = processor_health_clone.write().await;
-                *guard = health;
+    println!("Starting health check poller");
+    tokio::spawn(health::run_poller(
+        app_state.clone(),
+        storage.leader_lease.clone(),
+    ));
 
-                tokio::time::sleep(Duration::from_secs(5)).await;
+    println!("Starting circuit breaker sync");
+    let circuit_breaker_state = app_state.clone();
+    tokio::spawn(async move {
+        loop {
+            if let Err(e) = circuit_breaker_state
+                .payment_router
+                .circuit_breaker
+                .sync()
+                .await
+            {
+                eprintln!("Failed to sync circuit breaker: {e:?}");
             }
-        });
-    } else {
-        
-        println!("Starting health check thread");
-        tokio::spawn(async move {
-            loop {
-                let subscriber = &app_state_clone.health_check_channel.subcribe();
-
-                let msg = subscriber.get_message().await;
-
-
-                match msg {
-                    Ok(message) => {
-                        let health: payment_processors::structs::PaymentProcessorHealth = message.get_payload().unwrap();
-                        {
-                            let mut guard = processor_health_clone.write().await;
-                            *guard = health;
-                        }   
-                    }
-                    Err(e) => {
-                        eprintln!("Subscriber error: {:?}", e);
-                        break;
-                    }
-                }
+            tokio::time::sleep(Duration::from_millis(250)).await;
+        }
+    });
 
-            }
+    if let Some(flusher) = storage.flusher.clone() {
+        println!("Starting payment flusher");
+        tokio::spawn(async move {
+            flusher.run().await;
         });
     }
-        
-        
-        
 
-    
-    
-    
-    println!("Starting worker threads")
-    let mut workers = Vec::new();
-    for _ in 0..num_workers {
-        let worker_state = app_state.clone();
-
-        let tread = tokio::spawn(async move {
-            let redis_queue = &worker_state.redis_queue;
-            let memory_database = &worker_state.memory_database;
-            let client = &worker_state.http_client;
-            loop {
-                let health_guard = worker_state.processor_health.read().await;
-                if let Some(_service) = service::select_service(
-                    &health_guard,
-                ) {
-                    // If a service is available, process payments
-                    if let Ok(Some(payment)) = redis_queue.pop().await {
-                        let mut retries = 0;
-                        loop {
-                            match service::process_payment(
-                                memory_database,
-                                client,
-                                redis_queue,
-                                worker_state.processor_health.clone(),
-                                payment,
-                            )
-                            .await
-                            {
-                                Ok(_) => break,
-                                Err(e) => {
-                                    retries += 1;
-                                    if retries >= 100 {
-                                        eprintln!(
-                                            "Failed to process payment after 100 retries: {e:?}"
-                                        );
-                                        break;
-                                    }
-                                    tokio::time::sleep(Duration::from_millis(50)).await;
-                                }
-                            }
-                        }
-                    }
-                } 
-                tokio::time::sleep(Duration::from_millis(10)).await;
+    println!("Starting queue reaper");
+    let reaper_state = app_state.clone();
+    tokio::spawn(async move {
+        loop {
+            match reaper_state.queue.requeue_expired().await {
+                Ok(0) => {}
+                Ok(requeued) => eprintln!("Requeued {requeued} expired payments"),
+                Err(e) => eprintln!("Failed to requeue expired payments: {e:?}"),
             }
-        });
+            tokio::time::sleep(Duration::from_secs(1)).await;
+        }
+    });
 
-        workers.push(tread);
+    println!("Starting admission workers");
+    let admission_workers: Vec<_> = (0..config.admission.workers)
+        .map(|_| tokio::spawn(admission::run_worker(app_state.clone())))
+        .collect();
+
+    println!("Starting delayed retry release");
+    let release_state = app_state.clone();
+    tokio::spawn(async move {
+        let interval = Duration::from_millis(release_state.config.queue.release_interval_ms);
+        loop {
+            if let Err(e) = release_state.queue.release_due().await {
+                eprintln!("Failed to release delayed payments: {e:?}");
+            }
+            tokio::time::sleep(interval).await;
+        }
+    });
+
+    println!("Starting worker threads");
+    let mut workers = Vec::new();
+    for worker_id in 0..config.num_workers {
+        let consumer = format!("{}-{worker_id}", config.hostname);
+        workers.push(tokio::spawn(worker::run_worker(
+            app_state.clone(),
+            consumer,
+        )));
     }
 
 
 
-    println!("Starting server")
+    println!("Starting server");
     let priority_route = axum::Router::new()
         .route("/payments", axum::routing::post(controller::payments))
         .layer(ConcurrencyLimitLayer::new(1024));
@@ -235,28 +186,97 @@ async fn main() {
             "/payments-summary",
             axum::routing::get(controller::payments_summary),
         )
+        .route(
+            "/admin/summary-consistency",
+            axum::routing::get(controller::summary_consistency),
+        )
         .route(
             "/purge-payments",
             axum::routing::post(controller::purge_payments),
         )
+        .route(
+            "/admin/queue-depth",
+            axum::routing::get(controller::queue_depth),
+        )
+        .route(
+            "/admin/dead-letters",
+            axum::routing::get(controller::list_dead_letters)
+                .delete(controller::purge_dead_letters),
+        )
+        .route(
+            "/admin/dead-letters/replay",
+            axum::routing::post(controller::replay_dead_letters),
+        )
+        .route(
+            "/admin/dead-letters/{correlation_id}",
+            axum::routing::get(controller::get_dead_letter),
+        )
+        .route(
+            "/admin/dead-letters/{correlation_id}/replay",
+            axum::routing::post(controller::replay_dead_letter),
+        )
         .layer(ConcurrencyLimitLayer::new(32))
         .merge(priority_route)
         .with_state(app_state.clone());
 
 
-    let listener = std::net::TcpListener::bind(format!("0.0.0.0:{}", &port))
-        .unwrap_or_else(|_| panic!("error listening to socket 0.0.0.0:{}", &port));
+    let port = config.port;
+    let listener = std::net::TcpListener::bind(format!("0.0.0.0:{port}"))
+        .unwrap_or_else(|_| panic!("error listening to socket 0.0.0.0:{port}"));
     listener.set_nonblocking(true).unwrap();
 
     let listener = tokio::net::TcpListener::from_std(listener).expect("error parsing std listener");
 
     eprintln!("Server up!");
 
-    axum::serve(listener, app).await.unwrap();
+    axum::serve(listener, app)
+        .with_graceful_shutdown(app_state.shutdown.clone().on_signal())
+        .await
+        .unwrap();
+
+    eprintln!("Draining in-flight payments and workers");
+    let deadline = tokio::time::Instant::now() + Duration::from_millis(config.shutdown_timeout_ms);
+    if !app_state.in_flight.drain(deadline).await {
+        // Stop the admission workers first, so none of them goes on with a payment once it is
+        // requeued. One stopped mid-call leaves its payment tracked, with that processor as a
+        // suspect for the next instance to ask before sending it anywhere else.
+        for worker in &admission_workers {
+            worker.abort();
+        }
+        futures_util::future::join_all(admission_workers).await;
+        let unfinished = app_state.in_flight.take_all();
+        eprintln!("Requeueing {} unfinished payments", unfinished.len());
+        for queued in unfinished {
+            if let Err(e) = app_state.queue.push(queued).await {
+                eprintln!(
+                    "Failed to requeue payment {}: {e:?}",
+                    queued.payment.correlation_id
+                );
+            }
+        }
+    }
+
+    let all_workers = futures_util::future::join_all(workers.iter_mut());
+    if tokio::time::timeout_at(deadline, all_workers)
+        .await
+        .is_err()
+    {
+        // Deliveries these workers held are requeued by the reaper once they expire.
+        eprintln!("Workers did not stop in time, aborting them");
+        for worker in &workers {
+            worker.abort();
+        }
+    }
+
+    if let Err(e) = storage.leader_lease.release(&config.hostname).await {
+        eprintln!("Failed to release the health check lease: {e:?}");
+    }
 
-    // Wait for all worker threads to finish
-    if let Some(tread) = workers.into_iter().next() {
-        tread.await.unwrap();
+    if let Some(flusher) = storage.flusher.as_ref() {
+        match flusher.flush().await {
+            Ok(rows_written) => eprintln!("Flushed {rows_written} buffered payments"),
+            Err(e) => eprintln!("Failed to flush buffered payments: {e:?}"),
+        }
     }
     eprintln!("Server down!");
 }
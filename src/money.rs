@@ -0,0 +1,210 @@
+use std::{
+    fmt,
+    iter::Sum,
+    ops::{Add, AddAssign},
+    str::FromStr,
+};
+
+use serde::{Deserialize, Deserializer, Serialize, Serializer, de, ser};
+use serde_json::value::RawValue;
+
+/// A non-negative amount of money, stored as integer cents.
+/// Parses from and serializes to exact JSON decimals, never through `f64`.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Money(i64);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MoneyError {
+    Invalid,
+    Negative,
+    TooManyDecimals,
+    Overflow,
+}
+
+impl fmt::Display for MoneyError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            MoneyError::Invalid => write!(f, "amount must be a finite decimal number"),
+            MoneyError::Negative => write!(f, "amount must not be negative"),
+            MoneyError::TooManyDecimals => write!(f, "amount must have at most 2 decimal places"),
+            MoneyError::Overflow => write!(f, "amount is too large"),
+        }
+    }
+}
+
+impl std::error::Error for MoneyError {}
+
+impl Money {
+    pub const ZERO: Money = Money(0);
+
+    pub fn from_cents(cents: i64) -> Self {
+        Money(cents)
+    }
+
+    pub fn cents(self) -> i64 {
+        self.0
+    }
+}
+
+impl FromStr for Money {
+    type Err = MoneyError;
+
+    /// Parses a JSON number such as `19.9`, `19.90` or `1.99e1` into cents.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (negative, unsigned) = match s.strip_prefix('-') {
+            Some(rest) => (true, rest),
+            None => (false, s),
+        };
+        let (mantissa, exponent) = match unsigned.split_once(['e', 'E']) {
+            Some((mantissa, exponent)) => {
+                let exponent_digits = exponent.strip_prefix(['+', '-']).unwrap_or(exponent);
+                if exponent_digits.is_empty()
+                    || !exponent_digits.bytes().all(|b| b.is_ascii_digit())
+                {
+                    return Err(MoneyError::Invalid);
+                }
+                let exponent = exponent.parse::<i32>().map_err(|_| MoneyError::Overflow)?;
+                (mantissa, exponent)
+            }
+            None => (unsigned, 0),
+        };
+        let (integer, fraction) = mantissa.split_once('.').unwrap_or((mantissa, ""));
+        if integer.is_empty()
+            || (mantissa.contains('.') && fraction.is_empty())
+            || !integer
+                .bytes()
+                .chain(fraction.bytes())
+                .all(|b| b.is_ascii_digit())
+        {
+            return Err(MoneyError::Invalid);
+        }
+
+        // Value is `digits * 10^(scale - 2)`, so `digits * 10^scale` cents.
+        let digits = format!("{integer}{fraction}");
+        let digits = digits.trim_start_matches('0');
+        let scale = exponent
+            .checked_sub(fraction.len() as i32)
+            .and_then(|scale| scale.checked_add(2))
+            .ok_or(MoneyError::Overflow)?;
+
+        let cents = if digits.is_empty() {
+            0
+        } else if scale >= 0 {
+            let digits = digits.parse::<i64>().map_err(|_| MoneyError::Overflow)?;
+            10i64
+                .checked_pow(scale as u32)
+                .and_then(|factor| digits.checked_mul(factor))
+                .ok_or(MoneyError::Overflow)?
+        } else {
+            let dropped = scale.unsigned_abs() as usize;
+            if dropped > digits.len()
+                || !digits[digits.len() - dropped..].bytes().all(|b| b == b'0')
+            {
+                return Err(MoneyError::TooManyDecimals);
+            }
+            let kept = &digits[..digits.len() - dropped];
+            if kept.is_empty() {
+                0
+            } else {
+                kept.parse::<i64>().map_err(|_| MoneyError::Overflow)?
+            }
+        };
+
+        if negative && cents != 0 {
+            return Err(MoneyError::Negative);
+        }
+        Ok(Money(cents))
+    }
+}
+
+impl fmt::Display for Money {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let sign = if self.0 < 0 { "-" } else { "" };
+        let cents = self.0.unsigned_abs();
+        write!(f, "{sign}{}.{:02}", cents / 100, cents % 100)
+    }
+}
+
+impl Serialize for Money {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        RawValue::from_string(self.to_string())
+            .map_err(ser::Error::custom)?
+            .serialize(serializer)
+    }
+}
+
+impl<'de> Deserialize<'de> for Money {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let raw = Box::<RawValue>::deserialize(deserializer)?;
+        raw.get().parse().map_err(de::Error::custom)
+    }
+}
+
+impl Add for Money {
+    type Output = Money;
+
+    fn add(self, rhs: Money) -> Money {
+        Money(self.0 + rhs.0)
+    }
+}
+
+impl AddAssign for Money {
+    fn add_assign(&mut self, rhs: Money) {
+        self.0 += rhs.0;
+    }
+}
+
+impl Sum for Money {
+    fn sum<I: Iterator<Item = Money>>(iter: I) -> Money {
+        iter.fold(Money::ZERO, Add::add)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_json_decimals_into_cents() {
+        let table: &[(&str, Result<i64, MoneyError>)] = &[
+            ("19.90", Ok(1990)),
+            ("19.9", Ok(1990)),
+            ("19", Ok(1900)),
+            ("0", Ok(0)),
+            ("0.01", Ok(1)),
+            ("-0", Ok(0)),
+            ("1.99e1", Ok(1990)),
+            ("1990E-2", Ok(1990)),
+            ("0.1000", Ok(10)),
+            ("0.001", Err(MoneyError::TooManyDecimals)),
+            ("19.999", Err(MoneyError::TooManyDecimals)),
+            ("1e-3", Err(MoneyError::TooManyDecimals)),
+            ("-1.50", Err(MoneyError::Negative)),
+            ("NaN", Err(MoneyError::Invalid)),
+            ("\"19.90\"", Err(MoneyError::Invalid)),
+            ("1.", Err(MoneyError::Invalid)),
+            (".5", Err(MoneyError::Invalid)),
+            ("1e", Err(MoneyError::Invalid)),
+            ("99999999999999999999", Err(MoneyError::Overflow)),
+        ];
+
+        for (input, expected) in table {
+            assert_eq!(
+                input.parse::<Money>().map(Money::cents),
+                *expected,
+                "{input}"
+            );
+        }
+    }
+
+    #[test]
+    fn round_trips_through_json() {
+        let amount: Money = serde_json::from_str("19.9").unwrap();
+        assert_eq!(serde_json::to_string(&amount).unwrap(), "19.90");
+        assert_eq!(
+            serde_json::to_string(&Money::from_cents(5)).unwrap(),
+            "0.05"
+        );
+        assert!(serde_json::from_str::<Money>("19.999").is_err());
+    }
+}
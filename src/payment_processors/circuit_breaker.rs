@@ -0,0 +1,445 @@
+use std::sync::{
+    Arc, LazyLock, Mutex,
+    atomic::{AtomicBool, AtomicI64, Ordering},
+};
+
+use bb8::{Pool, PooledConnection};
+use bb8_redis::RedisConnectionManager;
+use chrono::Utc;
+use redis::Script;
+
+use crate::{config::CircuitBreakerConfig, payment_processors::service::PaymentProcessorServices};
+
+pub(crate) type CircuitBreakerConnection = Pool<RedisConnectionManager>;
+
+// Marks a circuit that has not closed since it tripped, so an expired open key means half-open.
+const HALF_OPEN: i64 = 1;
+const CLOSED: i64 = 0;
+
+// Counts a failure inside the window and trips the circuit when the threshold is reached,
+// or straight away when the failed call was the half-open probe.
+// Returns how long the circuit stays open, or 0 when it is still closed.
+static RECORD_FAILURE_SCRIPT: LazyLock<Script> = LazyLock::new(|| {
+    Script::new(
+        r"
+        local open_ttl = redis.call('PTTL', KEYS[1])
+        if open_ttl > 0 then
+            return open_ttl
+        end
+        local failures = redis.call('INCR', KEYS[2])
+        if failures == 1 then
+            redis.call('PEXPIRE', KEYS[2], ARGV[2])
+        end
+        if failures >= tonumber(ARGV[1]) or redis.call('EXISTS', KEYS[3]) == 1 then
+            redis.call('SET', KEYS[1], 1, 'PX', ARGV[3])
+            redis.call('SET', KEYS[3], 1)
+            redis.call('DEL', KEYS[2])
+            return tonumber(ARGV[3])
+        end
+        return 0
+        ",
+    )
+});
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CircuitState {
+    Closed,
+    Open,
+    HalfOpen,
+}
+
+#[derive(Debug, Default)]
+struct LocalCircuit {
+    /// `CLOSED`, `HALF_OPEN`, or the epoch millisecond the open circuit turns half-open.
+    open_until_ms: AtomicI64,
+    probe_in_flight: AtomicBool,
+    /// Failure count and window start, used only when there is no Redis to count in.
+    local_failures: Mutex<(u32, i64)>,
+}
+
+impl LocalCircuit {
+    fn state(&self, now_ms: i64) -> CircuitState {
+        match self.open_until_ms.load(Ordering::Acquire) {
+            CLOSED => CircuitState::Closed,
+            open_until_ms if now_ms < open_until_ms => CircuitState::Open,
+            _ => CircuitState::HalfOpen,
+        }
+    }
+}
+
+/// Leave to make one call through a circuit, handed back through `record_success` or
+/// `record_failure`. A permit for the half-open probe clears the probe when dropped, so a
+/// probe whose task is cancelled mid-call doesn't keep the circuit from probing again.
+#[derive(Debug)]
+pub struct CircuitPermit<'a> {
+    service: PaymentProcessorServices,
+    probe: Option<&'a AtomicBool>,
+}
+
+impl Drop for CircuitPermit<'_> {
+    fn drop(&mut self) {
+        if let Some(probe_in_flight) = self.probe {
+            probe_in_flight.store(false, Ordering::Release);
+        }
+    }
+}
+
+/// Per-processor circuit breaker fed by the outcome of real payment calls.
+/// Failure counts and trips live in Redis so every instance opens and closes together;
+/// each instance keeps a local copy so routing never waits on Redis.
+/// Without a pool the breaker counts locally and only this instance sees it.
+#[derive(Debug, Clone)]
+pub struct CircuitBreaker {
+    pool: Option<CircuitBreakerConnection>,
+    key_prefix: String,
+    failure_threshold: u32,
+    failure_window_ms: u64,
+    open_duration_ms: u64,
+    circuits: Arc<[LocalCircuit; 2]>,
+}
+
+impl CircuitBreaker {
+    pub fn new(pool: CircuitBreakerConnection, config: &CircuitBreakerConfig) -> Self {
+        Self::with_pool(Some(pool), config)
+    }
+
+    /// A breaker for a single instance, with no Redis behind it.
+    pub fn local(config: &CircuitBreakerConfig) -> Self {
+        Self::with_pool(None, config)
+    }
+
+    fn with_pool(pool: Option<CircuitBreakerConnection>, config: &CircuitBreakerConfig) -> Self {
+        Self {
+            pool,
+            key_prefix: config.key_prefix.clone(),
+            failure_threshold: config.failure_threshold,
+            failure_window_ms: config.failure_window_ms,
+            open_duration_ms: config.open_duration_ms,
+            circuits: Arc::new([LocalCircuit::default(), LocalCircuit::default()]),
+        }
+    }
+
+    pub fn state(&self, service: PaymentProcessorServices) -> CircuitState {
+        self.circuit(service).state(Utc::now().timestamp_millis())
+    }
+
+    /// A permit to call `service` if it may go ahead. A half-open circuit lets a single probe
+    /// through.
+    pub fn try_acquire(&self, service: PaymentProcessorServices) -> Option<CircuitPermit<'_>> {
+        let circuit = self.circuit(service);
+        let probe = match circuit.state(Utc::now().timestamp_millis()) {
+            CircuitState::Closed => None,
+            CircuitState::Open => return None,
+            CircuitState::HalfOpen => {
+                circuit
+                    .probe_in_flight
+                    .compare_exchange(false, true, Ordering::AcqRel, Ordering::Acquire)
+                    .ok()?;
+                Some(&circuit.probe_in_flight)
+            }
+        };
+        Some(CircuitPermit { service, probe })
+    }
+
+    pub async fn record_success(
+        &self,
+        permit: CircuitPermit<'_>,
+    ) -> Result<(), bb8_redis::redis::RedisError> {
+        let service = permit.service;
+        let circuit = self.circuit(service);
+        let was_closed = circuit.open_until_ms.swap(CLOSED, Ordering::AcqRel) == CLOSED;
+        drop(permit);
+        if was_closed {
+            return Ok(());
+        }
+        if self.pool.is_none() {
+            *circuit.local_failures.lock().unwrap() = (0, 0);
+            return Ok(());
+        }
+
+        let mut conn = self.connection().await?;
+        let _: () = redis::cmd("DEL")
+            .arg(self.open_key(service))
+            .arg(self.failures_key(service))
+            .arg(self.tripped_key(service))
+            .query_async(&mut *conn)
+            .await?;
+        Ok(())
+    }
+
+    pub async fn record_failure(
+        &self,
+        permit: CircuitPermit<'_>,
+    ) -> Result<(), bb8_redis::redis::RedisError> {
+        let service = permit.service;
+        let open_for_ms = if self.pool.is_some() {
+            let mut conn = self.connection().await?;
+            RECORD_FAILURE_SCRIPT
+                .key(self.open_key(service))
+                .key(self.failures_key(service))
+                .key(self.tripped_key(service))
+                .arg(self.failure_threshold)
+                .arg(self.failure_window_ms)
+                .arg(self.open_duration_ms)
+                .invoke_async(&mut *conn)
+                .await?
+        } else {
+            self.record_local_failure(service)
+        };
+
+        let circuit = self.circuit(service);
+        if open_for_ms > 0 {
+            circuit.open_until_ms.store(
+                Utc::now().timestamp_millis() + open_for_ms,
+                Ordering::Release,
+            );
+        }
+        // Only now may another call probe, once the circuit has reopened if it is going to.
+        drop(permit);
+        Ok(())
+    }
+
+    /// Same as `RECORD_FAILURE_SCRIPT`, against this instance's own counters.
+    fn record_local_failure(&self, service: PaymentProcessorServices) -> i64 {
+        let circuit = self.circuit(service);
+        let now_ms = Utc::now().timestamp_millis();
+        let open_until_ms = circuit.open_until_ms.load(Ordering::Acquire);
+        if open_until_ms > HALF_OPEN && now_ms < open_until_ms {
+            return open_until_ms - now_ms;
+        }
+
+        let mut failures = circuit.local_failures.lock().unwrap();
+        let (count, window_started_ms) = *failures;
+        let count = if now_ms - window_started_ms < self.failure_window_ms as i64 {
+            count + 1
+        } else {
+            1
+        };
+        if count >= self.failure_threshold || open_until_ms != CLOSED {
+            *failures = (0, now_ms);
+            return self.open_duration_ms as i64;
+        }
+        *failures = (
+            count,
+            if count == 1 {
+                now_ms
+            } else {
+                window_started_ms
+            },
+        );
+        0
+    }
+
+    /// Pulls the circuits tripped or closed by other instances into the local copy.
+    pub async fn sync(&self) -> Result<(), bb8_redis::redis::RedisError> {
+        if self.pool.is_none() {
+            return Ok(());
+        }
+        let services = [
+            PaymentProcessorServices::Default,
+            PaymentProcessorServices::Fallback,
+        ];
+
+        let mut conn = self.connection().await?;
+        let mut pipeline = redis::pipe();
+        for service in services {
+            pipeline
+                .pttl(self.open_key(service))
+                .exists(self.tripped_key(service));
+        }
+        let (default_ttl_ms, default_tripped, fallback_ttl_ms, fallback_tripped): (
+            i64,
+            bool,
+            i64,
+            bool,
+        ) = pipeline.query_async(&mut *conn).await?;
+        let replies = [
+            (default_ttl_ms, default_tripped),
+            (fallback_ttl_ms, fallback_tripped),
+        ];
+
+        let now_ms = Utc::now().timestamp_millis();
+        for (service, (open_ttl_ms, tripped)) in services.into_iter().zip(replies) {
+            let open_until_ms = if open_ttl_ms > 0 {
+                now_ms + open_ttl_ms
+            } else if tripped {
+                HALF_OPEN
+            } else {
+                CLOSED
+            };
+
+            let circuit = self.circuit(service);
+            let previous = circuit.open_until_ms.swap(open_until_ms, Ordering::AcqRel);
+            if open_until_ms == CLOSED && previous != CLOSED {
+                circuit.probe_in_flight.store(false, Ordering::Release);
+            }
+        }
+        Ok(())
+    }
+
+    fn circuit(&self, service: PaymentProcessorServices) -> &LocalCircuit {
+        match service {
+            PaymentProcessorServices::Default => &self.circuits[0],
+            PaymentProcessorServices::Fallback => &self.circuits[1],
+        }
+    }
+
+    async fn connection(
+        &self,
+    ) -> Result<PooledConnection<'_, RedisConnectionManager>, bb8_redis::redis::RedisError> {
+        let pool = self.pool.as_ref().ok_or_else(|| {
+            bb8_redis::redis::RedisError::from((
+                bb8_redis::redis::ErrorKind::ClientError,
+                "circuit breaker has no Redis pool",
+            ))
+        })?;
+        pool.get().await.map_err(|e| {
+            bb8_redis::redis::RedisError::from((
+                bb8_redis::redis::ErrorKind::IoError,
+                "bb8 pool error",
+                e.to_string(),
+            ))
+        })
+    }
+
+    fn open_key(&self, service: PaymentProcessorServices) -> String {
+        format!("{}:{}:open", self.key_prefix, service)
+    }
+
+    fn failures_key(&self, service: PaymentProcessorServices) -> String {
+        format!("{}:{}:failures", self.key_prefix, service)
+    }
+
+    fn tripped_key(&self, service: PaymentProcessorServices) -> String {
+        format!("{}:{}:tripped", self.key_prefix, service)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn half_open_breaker() -> CircuitBreaker {
+        let breaker = CircuitBreaker::local(&CircuitBreakerConfig::default());
+        breaker.circuits[0]
+            .open_until_ms
+            .store(HALF_OPEN, Ordering::Release);
+        breaker
+    }
+
+    #[test]
+    fn half_open_circuit_lets_one_probe_through() {
+        let breaker = half_open_breaker();
+
+        let probe = breaker.try_acquire(PaymentProcessorServices::Default);
+        assert!(probe.is_some());
+        assert!(
+            breaker
+                .try_acquire(PaymentProcessorServices::Default)
+                .is_none()
+        );
+    }
+
+    #[test]
+    fn dropped_probe_lets_the_next_call_probe() {
+        let breaker = half_open_breaker();
+
+        // A probe whose task is cancelled mid-call never records an outcome.
+        drop(breaker.try_acquire(PaymentProcessorServices::Default));
+
+        assert_eq!(
+            breaker.state(PaymentProcessorServices::Default),
+            CircuitState::HalfOpen
+        );
+        assert!(
+            breaker
+                .try_acquire(PaymentProcessorServices::Default)
+                .is_some()
+        );
+    }
+
+    fn breaker(failure_window_ms: u64) -> CircuitBreaker {
+        CircuitBreaker::local(&CircuitBreakerConfig {
+            failure_threshold: 3,
+            failure_window_ms,
+            ..CircuitBreakerConfig::default()
+        })
+    }
+
+    async fn fail(breaker: &CircuitBreaker) {
+        let permit = breaker.try_acquire(PaymentProcessorServices::Default);
+        breaker.record_failure(permit.unwrap()).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn trips_once_failures_reach_the_threshold() {
+        let breaker = breaker(60_000);
+
+        fail(&breaker).await;
+        fail(&breaker).await;
+        assert_eq!(
+            breaker.state(PaymentProcessorServices::Default),
+            CircuitState::Closed
+        );
+        fail(&breaker).await;
+        assert_eq!(
+            breaker.state(PaymentProcessorServices::Default),
+            CircuitState::Open
+        );
+        assert!(
+            breaker
+                .try_acquire(PaymentProcessorServices::Default)
+                .is_none()
+        );
+        assert_eq!(
+            breaker.state(PaymentProcessorServices::Fallback),
+            CircuitState::Closed
+        );
+    }
+
+    #[tokio::test]
+    async fn forgets_failures_outside_the_window() {
+        let breaker = breaker(20);
+
+        fail(&breaker).await;
+        fail(&breaker).await;
+        tokio::time::sleep(std::time::Duration::from_millis(30)).await;
+        fail(&breaker).await;
+        assert_eq!(
+            breaker.state(PaymentProcessorServices::Default),
+            CircuitState::Closed
+        );
+    }
+
+    #[tokio::test]
+    async fn successful_probe_closes_the_circuit() {
+        let breaker = half_open_breaker();
+
+        let probe = breaker.try_acquire(PaymentProcessorServices::Default);
+        breaker.record_success(probe.unwrap()).await.unwrap();
+        assert_eq!(
+            breaker.state(PaymentProcessorServices::Default),
+            CircuitState::Closed
+        );
+
+        // It starts counting afresh.
+        for _ in 1..CircuitBreakerConfig::default().failure_threshold {
+            fail(&breaker).await;
+        }
+        assert_eq!(
+            breaker.state(PaymentProcessorServices::Default),
+            CircuitState::Closed
+        );
+    }
+
+    #[tokio::test]
+    async fn failed_probe_reopens_the_circuit() {
+        let breaker = half_open_breaker();
+
+        fail(&breaker).await;
+        assert_eq!(
+            breaker.state(PaymentProcessorServices::Default),
+            CircuitState::Open
+        );
+    }
+}
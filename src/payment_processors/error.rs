@@ -0,0 +1,120 @@
+use std::fmt;
+
+use reqwest::StatusCode;
+
+/// Why a payment processor did not take a payment.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ProcessorError {
+    /// The processor failed or could not be reached. Another try may work.
+    Transient { status: StatusCode, message: String },
+    /// The processor rejected the payment itself (400 or 422). Trying again won't change its
+    /// mind. A 422 is also how a processor may turn away a payment it already has, so the
+    /// caller looks the payment up before giving up on it.
+    Permanent { status: StatusCode, message: String },
+    /// The processor turned the request away as unauthorized or not found (401, 403 or 404):
+    /// a wrong URL or credentials on this instance. Says nothing about the payment or the
+    /// processor's health.
+    Configuration { status: StatusCode, message: String },
+    /// The processor already has a payment with this correlation id (409).
+    Duplicate,
+    /// The processor asked us to slow down. Says nothing about the payment or its health.
+    RateLimited,
+    /// No answer in time, or the connection dropped after the payment was sent.
+    /// The processor may or may not have it.
+    Timeout(String),
+}
+
+impl ProcessorError {
+    /// Classifies a non-200 answer from the processor by its status. `message` is the body's
+    /// `message`, kept for the logs.
+    pub fn from_response(status: StatusCode, message: String) -> Self {
+        match status {
+            StatusCode::CONFLICT => ProcessorError::Duplicate,
+            StatusCode::UNAUTHORIZED | StatusCode::FORBIDDEN | StatusCode::NOT_FOUND => {
+                ProcessorError::Configuration { status, message }
+            }
+            StatusCode::TOO_MANY_REQUESTS => ProcessorError::RateLimited,
+            StatusCode::REQUEST_TIMEOUT => ProcessorError::Timeout(message),
+            StatusCode::BAD_REQUEST | StatusCode::UNPROCESSABLE_ENTITY => {
+                ProcessorError::Permanent { status, message }
+            }
+            status => ProcessorError::Transient { status, message },
+        }
+    }
+
+    pub fn status(&self) -> StatusCode {
+        match self {
+            ProcessorError::Transient { status, .. }
+            | ProcessorError::Permanent { status, .. }
+            | ProcessorError::Configuration { status, .. } => *status,
+            ProcessorError::Duplicate => StatusCode::UNPROCESSABLE_ENTITY,
+            ProcessorError::RateLimited => StatusCode::TOO_MANY_REQUESTS,
+            ProcessorError::Timeout(_) => StatusCode::GATEWAY_TIMEOUT,
+        }
+    }
+
+    /// Whether the failure counts against the processor's circuit.
+    pub fn is_processor_failure(&self) -> bool {
+        matches!(
+            self,
+            ProcessorError::Transient { .. } | ProcessorError::Timeout(_)
+        )
+    }
+
+    /// Whether the processor may have processed the payment all the same.
+    pub fn is_ambiguous(&self) -> bool {
+        matches!(self, ProcessorError::Timeout(_))
+    }
+}
+
+impl fmt::Display for ProcessorError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ProcessorError::Transient { status, message } => {
+                write!(f, "transient ({status}): {message}")
+            }
+            ProcessorError::Permanent { status, message } => {
+                write!(f, "permanent ({status}): {message}")
+            }
+            ProcessorError::Configuration { status, message } => {
+                write!(f, "configuration ({status}): {message}")
+            }
+            ProcessorError::Duplicate => write!(f, "duplicate: already processed"),
+            ProcessorError::RateLimited => write!(f, "rate limited"),
+            ProcessorError::Timeout(message) => write!(f, "timeout: {message}"),
+        }
+    }
+}
+
+impl std::error::Error for ProcessorError {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn classifies_processor_answers() {
+        let cases: &[(u16, &str, &str)] = &[
+            (409, "", "duplicate"),
+            (409, "Payment Already Processed", "duplicate"),
+            (422, "CorrelationId already exists", "permanent"),
+            (422, "amount must be positive", "permanent"),
+            (400, "bad request", "permanent"),
+            (401, "", "configuration"),
+            (403, "", "configuration"),
+            (404, "not found", "configuration"),
+            (429, "", "rate limited"),
+            (408, "", "timeout"),
+            (500, "boom", "transient"),
+            (503, "", "transient"),
+        ];
+        for (status, message, expected) in cases {
+            let status = StatusCode::from_u16(*status).unwrap();
+            let error = ProcessorError::from_response(status, message.to_string());
+            assert!(
+                error.to_string().starts_with(expected),
+                "{status} {message:?}: {error}"
+            );
+        }
+    }
+}
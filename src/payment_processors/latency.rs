@@ -0,0 +1,225 @@
+use std::{
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+use serde::{Deserialize, Serialize};
+
+use crate::payment_processors::{
+    service::PaymentProcessorServices,
+    structs::{PaymentProcessorHealth, PaymentProcessorHealthCheckDTO},
+};
+
+// Upper bounds of the histogram buckets, in milliseconds. Slower calls land in one more bucket,
+// reported as the last bound.
+const BUCKET_BOUNDS_MS: [u32; 13] = [
+    1, 2, 5, 10, 20, 50, 100, 200, 500, 1_000, 2_000, 5_000, 10_000,
+];
+// Once the histogram holds this many calls every count is halved, so old calls fade out.
+const HISTOGRAM_DECAY_AT: u64 = 1_024;
+
+/// Latency measured over recent calls to one processor.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct LatencyStats {
+    pub ewma_ms: f64,
+    pub p50_ms: u32,
+    pub p99_ms: u32,
+    pub samples: u64,
+}
+
+/// Measured latency per processor, where there were enough recent calls to tell.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize, Deserialize)]
+pub struct ObservedLatency {
+    pub default: Option<LatencyStats>,
+    pub fallback: Option<LatencyStats>,
+}
+
+#[derive(Debug, Default)]
+struct Histogram {
+    ewma_ms: Option<f64>,
+    buckets: [u64; BUCKET_BOUNDS_MS.len() + 1],
+    total: u64,
+    samples: u64,
+    last_sample_at: Option<Instant>,
+}
+
+impl Histogram {
+    fn percentile(&self, quantile: f64) -> u32 {
+        let rank = (self.total as f64 * quantile).ceil().max(1.0) as u64;
+        let mut seen = 0;
+        for (bucket, count) in self.buckets.iter().enumerate() {
+            seen += count;
+            if seen >= rank {
+                return BUCKET_BOUNDS_MS[bucket.min(BUCKET_BOUNDS_MS.len() - 1)];
+            }
+        }
+        BUCKET_BOUNDS_MS[BUCKET_BOUNDS_MS.len() - 1]
+    }
+}
+
+/// Times the calls made to one processor.
+#[derive(Debug)]
+pub struct ProcessorLatency {
+    alpha: f64,
+    min_samples: u64,
+    histogram: Mutex<Histogram>,
+}
+
+impl ProcessorLatency {
+    fn new(alpha: f64, min_samples: u64) -> Self {
+        Self {
+            alpha,
+            min_samples,
+            histogram: Mutex::new(Histogram::default()),
+        }
+    }
+
+    pub fn record(&self, elapsed: Duration) {
+        let ms = elapsed.as_secs_f64() * 1000.0;
+        let bucket = BUCKET_BOUNDS_MS.partition_point(|&bound| f64::from(bound) < ms);
+
+        let mut histogram = self.histogram.lock().unwrap_or_else(|e| e.into_inner());
+        histogram.ewma_ms = Some(match histogram.ewma_ms {
+            Some(ewma) => ewma + self.alpha * (ms - ewma),
+            None => ms,
+        });
+        if histogram.total >= HISTOGRAM_DECAY_AT {
+            histogram.buckets.iter_mut().for_each(|count| *count /= 2);
+            histogram.total = histogram.buckets.iter().sum();
+        }
+        histogram.buckets[bucket] += 1;
+        histogram.total += 1;
+        histogram.samples += 1;
+        histogram.last_sample_at = Some(Instant::now());
+    }
+
+    /// `None` until `min_samples` calls were timed, or when the last one is older than `max_age`.
+    pub fn stats(&self, max_age: Duration) -> Option<LatencyStats> {
+        let histogram = self.histogram.lock().unwrap_or_else(|e| e.into_inner());
+        let fresh = histogram
+            .last_sample_at
+            .is_some_and(|at| at.elapsed() <= max_age);
+        if !fresh || histogram.samples < self.min_samples {
+            return None;
+        }
+        Some(LatencyStats {
+            ewma_ms: histogram.ewma_ms?,
+            p50_ms: histogram.percentile(0.5),
+            p99_ms: histogram.percentile(0.99),
+            samples: histogram.samples,
+        })
+    }
+}
+
+/// Latency of the payment calls this instance made, per processor. Unlike the health
+/// endpoint's `min_response_time` it moves with every call, not every poll.
+#[derive(Debug)]
+pub struct LatencyTracker {
+    default: ProcessorLatency,
+    fallback: ProcessorLatency,
+}
+
+impl LatencyTracker {
+    pub fn new(alpha: f64, min_samples: u64) -> Self {
+        Self {
+            default: ProcessorLatency::new(alpha, min_samples),
+            fallback: ProcessorLatency::new(alpha, min_samples),
+        }
+    }
+
+    pub fn processor(&self, service: PaymentProcessorServices) -> &ProcessorLatency {
+        match service {
+            PaymentProcessorServices::Default => &self.default,
+            PaymentProcessorServices::Fallback => &self.fallback,
+        }
+    }
+
+    pub fn observed(&self, max_age: Duration) -> ObservedLatency {
+        ObservedLatency {
+            default: self.default.stats(max_age),
+            fallback: self.fallback.stats(max_age),
+        }
+    }
+
+    /// `health` with each processor's `min_response_time` replaced by the measured EWMA:
+    /// this instance's own if it has one, else `shared`, as broadcast by the poller.
+    pub fn apply(
+        &self,
+        health: PaymentProcessorHealth,
+        shared: &ObservedLatency,
+        max_age: Duration,
+    ) -> PaymentProcessorHealth {
+        let local = self.observed(max_age);
+        let measured = |check: PaymentProcessorHealthCheckDTO,
+                        local: Option<LatencyStats>,
+                        shared: Option<LatencyStats>| {
+            match local.or(shared) {
+                Some(stats) => PaymentProcessorHealthCheckDTO {
+                    min_response_time: stats.ewma_ms.round() as i32,
+                    ..check
+                },
+                None => check,
+            }
+        };
+        PaymentProcessorHealth {
+            default: measured(health.default, local.default, shared.default),
+            fallback: measured(health.fallback, local.fallback, shared.fallback),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    type Case = (&'static [u64], Option<(f64, u32, u32)>);
+
+    #[test]
+    fn summarises_timed_calls() {
+        let max_age = Duration::from_secs(10);
+        #[rustfmt::skip]
+        let cases: &[Case] = &[
+            (&[], None),
+            (&[10, 10], None),
+            (&[10, 10, 10], Some((10.0, 10, 10))),
+            (&[10, 10, 10, 90], Some((50.0, 10, 100))),
+            (&[4, 4, 4, 4, 20_000], Some((10_002.0, 5, 10_000))),
+        ];
+        for (calls_ms, expected) in cases {
+            let latency = ProcessorLatency::new(0.5, 3);
+            for ms in *calls_ms {
+                latency.record(Duration::from_millis(*ms));
+            }
+            let stats = latency
+                .stats(max_age)
+                .map(|stats| (stats.ewma_ms, stats.p50_ms, stats.p99_ms));
+            assert_eq!(stats, *expected, "{calls_ms:?}");
+        }
+    }
+
+    #[test]
+    fn prefers_local_measurements_over_shared_ones() {
+        let tracker = LatencyTracker::new(1.0, 1);
+        tracker
+            .processor(PaymentProcessorServices::Default)
+            .record(Duration::from_millis(40));
+        let shared = ObservedLatency {
+            default: None,
+            fallback: Some(LatencyStats {
+                ewma_ms: 25.4,
+                p50_ms: 20,
+                p99_ms: 50,
+                samples: 100,
+            }),
+        };
+
+        let health = tracker.apply(
+            PaymentProcessorHealth::UNKNOWN,
+            &shared,
+            Duration::from_secs(10),
+        );
+        assert_eq!(health.default.min_response_time, 40);
+        assert_eq!(health.fallback.min_response_time, 25);
+        assert!(!health.default.failing);
+    }
+}
@@ -1,2 +1,6 @@
+pub mod circuit_breaker;
+pub mod error;
+pub mod latency;
+pub mod routing;
 pub mod structs;
 pub mod service;
\ No newline at end of file
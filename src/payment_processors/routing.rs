@@ -0,0 +1,389 @@
+use std::{
+    fmt,
+    str::FromStr,
+    sync::{
+        Arc,
+        atomic::{AtomicBool, Ordering},
+    },
+    time::Duration,
+};
+
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    config::{ProcessorsConfig, RoutingConfig},
+    payment_processors::{
+        circuit_breaker::{CircuitBreaker, CircuitState},
+        latency::{LatencyTracker, ObservedLatency},
+        service::PaymentProcessorServices,
+        structs::{HealthSnapshot, PaymentProcessorHealth, PaymentProcessorHealthCheckDTO},
+    },
+};
+
+// Default has to get this much faster than the switch point before the adaptive policy returns to it.
+const ADAPTIVE_HYSTERESIS: f64 = 0.8;
+
+/// Decides which payment processors a payment may be sent to.
+pub trait RoutingPolicy: fmt::Debug + Send + Sync {
+    /// Processors to try, most preferred first. Empty when the payment should wait in the queue.
+    fn route(&self, health: &PaymentProcessorHealth) -> Vec<PaymentProcessorServices>;
+}
+
+fn services_with_health(
+    health: &PaymentProcessorHealth,
+) -> [(PaymentProcessorServices, &PaymentProcessorHealthCheckDTO); 2] {
+    [
+        (PaymentProcessorServices::Default, &health.default),
+        (PaymentProcessorServices::Fallback, &health.fallback),
+    ]
+}
+
+/// Any processor that is not failing, Default first.
+#[derive(Debug, Clone, Default)]
+pub struct StrictAvailabilityPolicy;
+
+impl RoutingPolicy for StrictAvailabilityPolicy {
+    fn route(&self, health: &PaymentProcessorHealth) -> Vec<PaymentProcessorServices> {
+        services_with_health(health)
+            .into_iter()
+            .filter(|(_, check)| !check.failing)
+            .map(|(service, _)| service)
+            .collect()
+    }
+}
+
+/// Processors that are not failing and answer within `max_response_time`, Default first.
+/// When none answers that fast, the faster one that is not failing, so payments keep flowing.
+#[derive(Debug, Clone)]
+pub struct LatencyThresholdPolicy {
+    max_response_time: i32,
+}
+
+impl LatencyThresholdPolicy {
+    pub fn new(max_response_time: i32) -> Self {
+        Self { max_response_time }
+    }
+}
+
+impl RoutingPolicy for LatencyThresholdPolicy {
+    fn route(&self, health: &PaymentProcessorHealth) -> Vec<PaymentProcessorServices> {
+        let available = services_with_health(health)
+            .into_iter()
+            .filter(|(_, check)| !check.failing);
+        let fast: Vec<_> = available
+            .clone()
+            .filter(|(_, check)| check.min_response_time <= self.max_response_time)
+            .map(|(service, _)| service)
+            .collect();
+        if !fast.is_empty() {
+            return fast;
+        }
+        // min_by_key keeps Default on a tie.
+        available
+            .min_by_key(|(_, check)| check.min_response_time)
+            .map(|(service, _)| service)
+            .into_iter()
+            .collect()
+    }
+}
+
+/// Orders healthy processors by fee plus a per-millisecond latency penalty.
+#[derive(Debug, Clone)]
+pub struct CostWeightedPolicy {
+    default_fee: f64,
+    fallback_fee: f64,
+    latency_penalty: f64,
+}
+
+impl CostWeightedPolicy {
+    pub fn new(default_fee: f64, fallback_fee: f64, latency_penalty: f64) -> Self {
+        Self {
+            default_fee,
+            fallback_fee,
+            latency_penalty,
+        }
+    }
+
+    fn cost(
+        &self,
+        service: PaymentProcessorServices,
+        check: &PaymentProcessorHealthCheckDTO,
+    ) -> f64 {
+        let fee = match service {
+            PaymentProcessorServices::Default => self.default_fee,
+            PaymentProcessorServices::Fallback => self.fallback_fee,
+        };
+        fee + f64::from(check.min_response_time.max(0)) * self.latency_penalty
+    }
+}
+
+impl RoutingPolicy for CostWeightedPolicy {
+    fn route(&self, health: &PaymentProcessorHealth) -> Vec<PaymentProcessorServices> {
+        let mut services: Vec<_> = services_with_health(health)
+            .into_iter()
+            .filter(|(_, check)| !check.failing)
+            .map(|(service, check)| (service, self.cost(service, check)))
+            .collect();
+        // Stable sort keeps Default ahead on equal cost.
+        services.sort_by(|a, b| a.1.total_cmp(&b.1));
+        services.into_iter().map(|(service, _)| service).collect()
+    }
+}
+
+/// Prefers Default until it gets `ratio` times slower than Fallback, and only goes back
+/// once it recovers past a hysteresis margin, so routing doesn't flap between polls.
+#[derive(Debug)]
+pub struct AdaptivePolicy {
+    ratio: f64,
+    prefer_fallback: AtomicBool,
+}
+
+impl AdaptivePolicy {
+    pub fn new(ratio: f64) -> Self {
+        Self {
+            ratio,
+            prefer_fallback: AtomicBool::new(false),
+        }
+    }
+}
+
+impl RoutingPolicy for AdaptivePolicy {
+    fn route(&self, health: &PaymentProcessorHealth) -> Vec<PaymentProcessorServices> {
+        let (default, fallback) = (&health.default, &health.fallback);
+        match (default.failing, fallback.failing) {
+            (true, true) => return Vec::new(),
+            (false, true) => return vec![PaymentProcessorServices::Default],
+            (true, false) => return vec![PaymentProcessorServices::Fallback],
+            (false, false) => {}
+        }
+
+        let default_latency = f64::from(default.min_response_time.max(0));
+        let switch_point = f64::from(fallback.min_response_time.max(1)) * self.ratio;
+        let prefer_fallback = if self.prefer_fallback.load(Ordering::Relaxed) {
+            default_latency > switch_point * ADAPTIVE_HYSTERESIS
+        } else {
+            default_latency > switch_point
+        };
+        self.prefer_fallback
+            .store(prefer_fallback, Ordering::Relaxed);
+
+        if prefer_fallback {
+            vec![
+                PaymentProcessorServices::Fallback,
+                PaymentProcessorServices::Default,
+            ]
+        } else {
+            vec![
+                PaymentProcessorServices::Default,
+                PaymentProcessorServices::Fallback,
+            ]
+        }
+    }
+}
+
+/// The routing policies that can be picked by name in the config.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum RoutingPolicyName {
+    Strict,
+    Latency,
+    Cost,
+    Adaptive,
+}
+
+impl FromStr for RoutingPolicyName {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "strict" => Ok(RoutingPolicyName::Strict),
+            "latency" => Ok(RoutingPolicyName::Latency),
+            "cost" => Ok(RoutingPolicyName::Cost),
+            "adaptive" => Ok(RoutingPolicyName::Adaptive),
+            _ => Err("expected strict, latency, cost or adaptive".to_string()),
+        }
+    }
+}
+
+/// Builds the policy named by `routing.policy`.
+pub fn policy_from_config(
+    routing: &RoutingConfig,
+    processors: &ProcessorsConfig,
+) -> Arc<dyn RoutingPolicy> {
+    match routing.policy {
+        RoutingPolicyName::Strict => Arc::new(StrictAvailabilityPolicy),
+        RoutingPolicyName::Latency => {
+            Arc::new(LatencyThresholdPolicy::new(routing.max_response_time))
+        }
+        RoutingPolicyName::Cost => Arc::new(CostWeightedPolicy::new(
+            processors.default_fee,
+            processors.fallback_fee,
+            routing.latency_penalty,
+        )),
+        RoutingPolicyName::Adaptive => Arc::new(AdaptivePolicy::new(routing.adaptive_ratio)),
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct PaymentRouter {
+    policy: Arc<dyn RoutingPolicy>,
+    pub circuit_breaker: CircuitBreaker,
+    pub latency: Arc<LatencyTracker>,
+    /// How long after the first attempt a payment may still fail over to the next processor.
+    pub failover_budget: Duration,
+    /// Older health snapshots are routed as if nothing were known.
+    pub max_health_age: Duration,
+    /// Time a processor gets beyond its expected response time before the call is abandoned.
+    pub attempt_timeout_margin: Duration,
+    /// Longest a call may take, however slow a processor says it is.
+    pub max_attempt_timeout: Duration,
+    /// How long a payment may spend across all its deliveries, attempts and fallbacks.
+    pub payment_budget: Duration,
+}
+
+impl PaymentRouter {
+    pub fn from_config(
+        routing: &RoutingConfig,
+        processors: &ProcessorsConfig,
+        circuit_breaker: CircuitBreaker,
+    ) -> Self {
+        Self {
+            policy: policy_from_config(routing, processors),
+            circuit_breaker,
+            latency: Arc::new(LatencyTracker::new(
+                routing.latency_ewma_alpha,
+                routing.latency_min_samples,
+            )),
+            failover_budget: Duration::from_millis(routing.failover_budget_ms),
+            max_health_age: Duration::from_millis(routing.max_health_age_ms),
+            attempt_timeout_margin: Duration::from_millis(routing.attempt_timeout_margin_ms),
+            max_attempt_timeout: Duration::from_millis(routing.max_attempt_timeout_ms),
+            payment_budget: Duration::from_millis(routing.payment_budget_ms),
+        }
+    }
+
+    /// The health to route by: `snapshot`'s, with measured latencies standing in for the
+    /// reported ones.
+    pub fn health(&self, snapshot: &HealthSnapshot) -> PaymentProcessorHealth {
+        let shared = if snapshot.age() > self.max_health_age {
+            ObservedLatency::default()
+        } else {
+            snapshot.observed
+        };
+        self.latency.apply(
+            snapshot.routable(self.max_health_age),
+            &shared,
+            self.max_health_age,
+        )
+    }
+
+    pub fn route(&self, snapshot: &HealthSnapshot) -> Vec<PaymentProcessorServices> {
+        self.route_health(&self.health(snapshot))
+    }
+
+    /// The policy's route for `health`, minus processors whose circuit is open.
+    pub fn route_health(&self, health: &PaymentProcessorHealth) -> Vec<PaymentProcessorServices> {
+        let mut services = self.policy.route(health);
+        services.retain(|service| self.circuit_breaker.state(*service) != CircuitState::Open);
+        services
+    }
+
+    /// How long to wait on a processor expected to answer within `check.min_response_time`,
+    /// up to `max_attempt_timeout`.
+    pub fn attempt_timeout(&self, check: &PaymentProcessorHealthCheckDTO) -> Duration {
+        let expected = Duration::from_millis(check.min_response_time.max(0) as u64);
+        (expected + self.attempt_timeout_margin).min(self.max_attempt_timeout)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use PaymentProcessorServices::{Default as D, Fallback as F};
+
+    type Route = &'static [PaymentProcessorServices];
+
+    fn health(
+        default_failing: bool,
+        default_ms: i32,
+        fallback_failing: bool,
+        fallback_ms: i32,
+    ) -> PaymentProcessorHealth {
+        PaymentProcessorHealth {
+            default: PaymentProcessorHealthCheckDTO {
+                failing: default_failing,
+                min_response_time: default_ms,
+            },
+            fallback: PaymentProcessorHealthCheckDTO {
+                failing: fallback_failing,
+                min_response_time: fallback_ms,
+            },
+        }
+    }
+
+    #[test]
+    fn routes_each_policy_against_health_table() {
+        let strict = StrictAvailabilityPolicy;
+        let latency = LatencyThresholdPolicy::new(100);
+        let cost = CostWeightedPolicy::new(0.05, 0.15, 0.0001);
+
+        #[rustfmt::skip]
+        let table: &[(PaymentProcessorHealth, Route, Route, Route)] = &[
+            // health                          strict   latency  cost
+            (health(false, 0, false, 0),       &[D, F], &[D, F], &[D, F]),
+            (health(false, 50, false, 5000),   &[D, F], &[D],    &[D, F]),
+            (health(false, 500, false, 10),    &[D, F], &[F],    &[D, F]),
+            (health(false, 1500, false, 10),   &[D, F], &[F],    &[F, D]),
+            (health(true, 0, false, 10),       &[F],    &[F],    &[F]),
+            (health(true, 10, false, 500),     &[F],    &[F],    &[F]),
+            (health(false, 500, false, 300),   &[D, F], &[F],    &[D, F]),
+            (health(false, 300, false, 300),   &[D, F], &[D],    &[D, F]),
+            (health(false, 10, true, 0),       &[D],    &[D],    &[D]),
+            (health(true, 0, true, 0),         &[],     &[],     &[]),
+            (health(true, i32::MAX, true, i32::MAX), &[], &[],   &[]),
+        ];
+
+        for (health, expected_strict, expected_latency, expected_cost) in table {
+            assert_eq!(strict.route(health), *expected_strict, "strict {health:?}");
+            assert_eq!(
+                latency.route(health),
+                *expected_latency,
+                "latency {health:?}"
+            );
+            assert_eq!(cost.route(health), *expected_cost, "cost {health:?}");
+        }
+    }
+
+    #[test]
+    fn adaptive_switches_with_hysteresis() {
+        let adaptive = AdaptivePolicy::new(3.0);
+
+        let table: &[(PaymentProcessorHealth, Route)] = &[
+            (health(false, 100, false, 100), &[D, F]),
+            (health(false, 301, false, 100), &[F, D]),
+            // Still above 80% of the switch point, so it stays on Fallback.
+            (health(false, 250, false, 100), &[F, D]),
+            (health(false, 200, false, 100), &[D, F]),
+            (health(true, 0, false, 100), &[F]),
+            (health(false, 5000, true, 0), &[D]),
+            (health(true, 0, true, 0), &[]),
+        ];
+
+        for (health, expected) in table {
+            assert_eq!(adaptive.route(health), *expected, "adaptive {health:?}");
+        }
+    }
+
+    #[test]
+    fn caps_attempt_timeouts_however_slow_the_processor_reports() {
+        let router = PaymentRouter::from_config(
+            &RoutingConfig::default(),
+            &ProcessorsConfig::default(),
+            CircuitBreaker::local(&crate::config::CircuitBreakerConfig::default()),
+        );
+
+        let timeout = |ms| router.attempt_timeout(&health(false, ms, false, ms).default);
+        assert_eq!(timeout(100), Duration::from_millis(350));
+        assert_eq!(timeout(60_000), router.max_attempt_timeout);
+    }
+}
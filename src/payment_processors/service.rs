@@ -1,33 +1,28 @@
-use std::f32::INFINITY;
+use std::{
+    fmt,
+    time::{Duration, Instant},
+};
 
 use reqwest::StatusCode;
+use uuid::Uuid;
 
-use crate::payment_processors::structs::{PaymentProcessorDTO, PaymentProcessorHealthCheckDTO};
+use crate::payment_processors::{
+    error::ProcessorError,
+    latency::ProcessorLatency,
+    structs::{PaymentProcessorDTO, PaymentProcessorHealthCheckDTO, PaymentProcessorResponseDTO},
+};
 
-const PAYMENT_PROCESSOR_DEFAULT_URL: &str = "http://localhost:8001";
-const PAYMENT_PROCESSOR_FALLBACK_URL: &str = "http://localhost:8002";
-
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum PaymentProcessorServices {
     Default,
     Fallback,
 }
 
-impl PaymentProcessorServices {
-    pub fn get_url(&self) -> String {
+impl fmt::Display for PaymentProcessorServices {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         match self {
-            PaymentProcessorServices::Default => std::env::var("PAYMENT_PROCESSOR_DEFAULT_URL")
-                .unwrap_or(PAYMENT_PROCESSOR_DEFAULT_URL.to_string()),
-
-            PaymentProcessorServices::Fallback => std::env::var("PAYMENT_PROCESSOR_FALLBACK_URL")
-                .unwrap_or(PAYMENT_PROCESSOR_FALLBACK_URL.to_string()),
-        }
-    }
-
-    pub fn to_string(&self) -> String {
-        match self {
-            PaymentProcessorServices::Default => "default".to_string(),
-            PaymentProcessorServices::Fallback => "fallback".to_string(),
+            PaymentProcessorServices::Default => write!(f, "default"),
+            PaymentProcessorServices::Fallback => write!(f, "fallback"),
         }
     }
 }
@@ -48,62 +43,114 @@ impl From<&str> for PaymentProcessorServices {
     }
 }
 
+/// Sends `transaction` to the processor at `base_url`, giving up after `timeout`.
+/// The call is timed into `latency` whenever the processor answers or times out.
 pub async fn process_transaction(
     client: &reqwest::Client,
+    base_url: &str,
     transaction: &PaymentProcessorDTO,
-    service: PaymentProcessorServices,
-) -> Result<(), (StatusCode, String)> {
+    timeout: Duration,
+    latency: &ProcessorLatency,
+) -> Result<(), ProcessorError> {
+    let started_at = Instant::now();
     let response: Result<reqwest::Response, reqwest::Error> = client
-        .post(format!("{}/payments", service.get_url()))
+        .post(format!("{base_url}/payments"))
         .header("Content-Type", "application/json")
+        .timeout(timeout)
         .json(&transaction)
         .send()
         .await;
 
     match response {
         Ok(resp) => {
-            if resp.status() == StatusCode::OK {
-                Ok(())
-            } else {
-                let status = resp.status();
-                Err((status, "Failed to process transaction".to_string()))
+            latency.record(started_at.elapsed());
+            let status = resp.status();
+            if status == StatusCode::OK {
+                return Ok(());
             }
+            let message = resp
+                .json::<PaymentProcessorResponseDTO>()
+                .await
+                .map(|body| body.message)
+                .unwrap_or_else(|_| "Failed to process transaction".to_string());
+            Err(ProcessorError::from_response(status, message))
         }
-        Err(_err) => {
-            Err((
-                StatusCode::INTERNAL_SERVER_ERROR,
-                "Internal server error".to_string(),
+        Err(err) if err.is_timeout() => {
+            latency.record(started_at.elapsed());
+            Err(ProcessorError::Timeout(
+                "Payment processor timed out".to_string(),
             ))
         }
+        // Never connected, so the processor can't have seen the payment.
+        Err(err) if err.is_connect() => Err(ProcessorError::Transient {
+            status: StatusCode::SERVICE_UNAVAILABLE,
+            message: format!("Failed to connect to payment processor: {err}"),
+        }),
+        Err(err) => Err(ProcessorError::Timeout(format!(
+            "Connection to payment processor dropped: {err}"
+        ))),
+    }
+}
+
+/// Whether the processor at `base_url` has a payment with `correlation_id`.
+pub async fn find_payment(
+    client: &reqwest::Client,
+    base_url: &str,
+    correlation_id: Uuid,
+    timeout: Duration,
+) -> Result<bool, (StatusCode, String)> {
+    let response = client
+        .get(format!("{base_url}/payments/{correlation_id}"))
+        .timeout(timeout)
+        .send()
+        .await
+        .map_err(|err| {
+            (
+                StatusCode::BAD_GATEWAY,
+                format!("Failed to look payment up: {err}"),
+            )
+        })?;
+
+    match response.status() {
+        StatusCode::OK => Ok(true),
+        StatusCode::NOT_FOUND => Ok(false),
+        status => Err((status, "Failed to look payment up".to_string())),
     }
 }
 
 static PAYMENT_PROCESSOR_HEALTH_FAILING: PaymentProcessorHealthCheckDTO =
     PaymentProcessorHealthCheckDTO {
         failing: true,
-        min_response_time: INFINITY as i32,
+        min_response_time: i32::MAX,
     };
 
+/// `None` when the processor turned the check away for coming less than 5 seconds after
+/// the previous one, which says nothing about its health.
 pub async fn get_service_health(
     client: &reqwest::Client,
-    service: PaymentProcessorServices,
-) -> PaymentProcessorHealthCheckDTO {
+    base_url: &str,
+    timeout: Duration,
+) -> Option<PaymentProcessorHealthCheckDTO> {
     let response: Result<reqwest::Response, reqwest::Error> = client
-        .get(format!("{}/payments/service-health", service.get_url()))
+        .get(format!("{base_url}/payments/service-health"))
+        .timeout(timeout)
         .send()
         .await;
 
     match response {
         Ok(resp) => {
             if resp.status() == StatusCode::OK {
-                resp
-                    .json()
-                    .await
-                    .unwrap_or(PAYMENT_PROCESSOR_HEALTH_FAILING)
+                Some(
+                    resp.json()
+                        .await
+                        .unwrap_or(PAYMENT_PROCESSOR_HEALTH_FAILING),
+                )
+            } else if resp.status() == StatusCode::TOO_MANY_REQUESTS {
+                None
             } else {
-                PAYMENT_PROCESSOR_HEALTH_FAILING
+                Some(PAYMENT_PROCESSOR_HEALTH_FAILING)
             }
         }
-        Err(_err) => PAYMENT_PROCESSOR_HEALTH_FAILING,
+        Err(_err) => Some(PAYMENT_PROCESSOR_HEALTH_FAILING),
     }
 }
@@ -2,11 +2,13 @@ use chrono::{DateTime, Utc};
 use serde::{Deserialize, Serialize};
 use uuid::Uuid;
 
+use crate::{money::Money, payment_processors::latency::ObservedLatency};
+
 #[derive(Debug, Clone, Copy, Serialize, Deserialize)]
 pub struct PaymentProcessorDTO {
     #[serde(rename = "correlationId")]
     pub correlation_id: Uuid,
-    pub amount: f64,
+    pub amount: Money,
     #[serde(rename = "requestedAt")]
     pub requested_at: DateTime<Utc>,
 }
@@ -26,12 +28,70 @@ pub struct PaymentProcessorHealthCheckDTO {
 
 
 
-#[derive(Debug, Clone, Deserialize, Serialize)]
+#[derive(Debug, Clone, Copy, Deserialize, Serialize)]
 pub struct PaymentProcessorHealth {
     pub default: PaymentProcessorHealthCheckDTO,
     pub fallback: PaymentProcessorHealthCheckDTO,
 }
 
+impl PaymentProcessorHealth {
+    /// Nothing known about either processor: both are tried, and the circuit breaker
+    /// decides which ones to skip.
+    pub const UNKNOWN: Self = Self {
+        default: PaymentProcessorHealthCheckDTO {
+            failing: false,
+            min_response_time: 0,
+        },
+        fallback: PaymentProcessorHealthCheckDTO {
+            failing: false,
+            min_response_time: 0,
+        },
+    };
+}
+
+/// A health check as cached and broadcast to every instance.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct HealthSnapshot {
+    pub health: PaymentProcessorHealth,
+    pub observed_at: DateTime<Utc>,
+    /// Hostname of the instance that polled the processors.
+    pub source: String,
+    /// Grows with every poll, across leaders, so subscribers can drop messages that arrive
+    /// out of order.
+    pub sequence: u64,
+    /// Latency the poller measured on its own payment calls.
+    #[serde(default)]
+    pub observed: ObservedLatency,
+}
+
+impl HealthSnapshot {
+    /// What an instance knows before any snapshot arrives. Too old to route by.
+    pub fn unknown() -> Self {
+        Self {
+            health: PaymentProcessorHealth::UNKNOWN,
+            observed_at: DateTime::UNIX_EPOCH,
+            source: String::new(),
+            sequence: 0,
+            observed: ObservedLatency::default(),
+        }
+    }
+
+    /// How long ago the processors were polled. A snapshot stamped in the future counts as
+    /// just taken.
+    pub fn age(&self) -> std::time::Duration {
+        (Utc::now() - self.observed_at).to_std().unwrap_or_default()
+    }
+
+    /// The health to route by: unknown once the snapshot is older than `max_age`.
+    pub fn routable(&self, max_age: std::time::Duration) -> PaymentProcessorHealth {
+        if self.age() > max_age {
+            PaymentProcessorHealth::UNKNOWN
+        } else {
+            self.health
+        }
+    }
+}
+
 
 
 pub const PAYMENT_PROCESSOR_MAX_RESPONSE_TIME: i32 = 200; // Maximum response time in milliseconds for a payment processor to be considered healthy 
\ No newline at end of file
@@ -1,67 +1,132 @@
-use bb8::Pool;
-use bb8_redis::RedisConnectionManager;
-use redis::AsyncCommands;
-
-
-struct HealthCheckChannel {
-     pool: RedisQueueConnection,
-    channel_name: String,
-    publisher: 
-}
-pub(crate) type RedisChannelConnection = Pool<RedisConnectionManager>;
-
-impl HealthCheckChannel {
-     pub fn new(pool: RedisChannelConnection) -> Self {
-        let channel_name =
-            std::env::var("HEALTH_CHECK_CHANNEL").unwrap_or_else(|_| "healthcheck".to_string());
-
-        Self {
-            pool,
-            channel_name,
-            publisher
-        }
-    }
-
-
-
-    async pub update(self, msg: PaymentProcessorHealth) -> Result<(), bb8_redis::redis::RedisError> {
-
-        let mut conn = self.pool.get().await.map_err(|e| {
-            bb8_redis::redis::RedisError::from((
-                bb8_redis::redis::ErrorKind::IoError,
-                "bb8 pool error",
-                e.to_string(),
-            ))
-        })?;
-
-
-        let value = serde_json::to_string(&msg).map_err(|e| {
-            bb8_redis::redis::RedisError::from((
-                bb8_redis::redis::ErrorKind::ParseError,
-                "Serialization error",
-                e.to_string(),
-            ))
-        })?;
-
-
-        conn.publish(self.channel_name, value).await?;
-        OK(())
-    }
-
-
-    async pub subscribe(self) -> Result<redis::PubSub, bb8_redis::redis::RedisError> {
-        let mut conn = self.pool.get().await.map_err(|e| {
-            bb8_redis::redis::RedisError::from((
-                bb8_redis::redis::ErrorKind::IoError,
-                "bb8 pool error",
-                e.to_string(),
-            ))
-        })?;
-
-        let mut pubsub = con.as_pubsub();
-        pubsub.subscribe(&[self.channel_name])?;
-
-        pubsub
-    }
-
-}
\ No newline at end of file
+use std::{sync::Arc, time::Duration};
+
+use bb8::Pool;
+use bb8_redis::RedisConnectionManager;
+use futures_util::StreamExt;
+use redis::AsyncCommands;
+
+use crate::{
+    config::HealthCheckConfig, health::ProcessorHealthState,
+    payment_processors::structs::HealthSnapshot,
+};
+
+pub(crate) type RedisChannelConnection = Pool<RedisConnectionManager>;
+
+const RECONNECT_DELAY: Duration = Duration::from_secs(1);
+
+#[derive(Debug, Clone)]
+pub struct HealthCheckChannel {
+    pool: RedisChannelConnection,
+    client: redis::Client,
+    channel_name: String,
+    cache_key: String,
+}
+
+impl HealthCheckChannel {
+    pub fn new(
+        pool: RedisChannelConnection,
+        client: redis::Client,
+        config: &HealthCheckConfig,
+    ) -> Self {
+        Self {
+            pool,
+            client,
+            channel_name: config.channel.clone(),
+            cache_key: config.cache_key.clone(),
+        }
+    }
+
+    /// Stores `msg` under the cache key and publishes it, in one round trip.
+    pub async fn update(&self, msg: &HealthSnapshot) -> Result<(), bb8_redis::redis::RedisError> {
+        let mut conn = self.pool.get().await.map_err(|e| {
+            bb8_redis::redis::RedisError::from((
+                bb8_redis::redis::ErrorKind::IoError,
+                "bb8 pool error",
+                e.to_string(),
+            ))
+        })?;
+
+        let value = serde_json::to_string(msg).map_err(|e| {
+            bb8_redis::redis::RedisError::from((
+                bb8_redis::redis::ErrorKind::ParseError,
+                "Serialization error",
+                e.to_string(),
+            ))
+        })?;
+
+        let _: () = redis::pipe()
+            .atomic()
+            .set(&self.cache_key, &value)
+            .ignore()
+            .publish(&self.channel_name, &value)
+            .ignore()
+            .query_async(&mut *conn)
+            .await?;
+        Ok(())
+    }
+
+    /// The snapshot last stored by [`Self::update`], from any instance.
+    pub async fn latest(&self) -> Result<Option<HealthSnapshot>, bb8_redis::redis::RedisError> {
+        let mut conn = self.pool.get().await.map_err(|e| {
+            bb8_redis::redis::RedisError::from((
+                bb8_redis::redis::ErrorKind::IoError,
+                "bb8 pool error",
+                e.to_string(),
+            ))
+        })?;
+
+        let value: Option<String> = conn.get(&self.cache_key).await?;
+        value
+            .map(|value| serde_json::from_str(&value))
+            .transpose()
+            .map_err(|e| {
+                bb8_redis::redis::RedisError::from((
+                    bb8_redis::redis::ErrorKind::ParseError,
+                    "Deserialization error",
+                    e.to_string(),
+                ))
+            })
+    }
+
+    pub async fn subscribe(&self) -> Result<redis::aio::PubSub, bb8_redis::redis::RedisError> {
+        // A subscribed connection can't run regular commands, so it never comes from the pool.
+        let mut pubsub = self.client.get_async_pubsub().await?;
+        pubsub.subscribe(&self.channel_name).await?;
+        Ok(pubsub)
+    }
+
+    /// Applies every health snapshot published on the channel to `processor_health`,
+    /// resubscribing whenever the connection to Redis is lost.
+    pub async fn listen(&self, processor_health: Arc<ProcessorHealthState>) {
+        loop {
+            let pubsub = match self.subscribe().await {
+                Ok(pubsub) => pubsub,
+                Err(e) => {
+                    eprintln!("Subscriber error: {e:?}");
+                    tokio::time::sleep(RECONNECT_DELAY).await;
+                    continue;
+                }
+            };
+
+            let mut messages = pubsub.into_on_message();
+            while let Some(message) = messages.next().await {
+                let health = message
+                    .get_payload::<String>()
+                    .map_err(|e| e.to_string())
+                    .and_then(|payload| {
+                        serde_json::from_str::<HealthSnapshot>(&payload).map_err(|e| e.to_string())
+                    });
+
+                match health {
+                    Ok(snapshot) => {
+                        processor_health.apply(snapshot).await;
+                    }
+                    Err(e) => eprintln!("Invalid health check message: {e}"),
+                }
+            }
+
+            eprintln!("Health check subscription closed, reconnecting...");
+            tokio::time::sleep(RECONNECT_DELAY).await;
+        }
+    }
+}
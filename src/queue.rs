@@ -1,76 +1,749 @@
-use bb8::Pool;
+use std::{str::FromStr, sync::LazyLock, time::Duration};
+
+use bb8::{Pool, PooledConnection};
 use bb8_redis::RedisConnectionManager;
-use redis::AsyncCommands;
+use chrono::{DateTime, Utc};
+use redis::{
+    AsyncCommands, Script,
+    streams::{StreamAutoClaimOptions, StreamAutoClaimReply, StreamReadOptions, StreamReadReply},
+};
+use serde::{Deserialize, Serialize};
 
-use crate::{payment_processors::structs::PaymentProcessorDTO};
+use crate::{
+    config::QueueConfig, payment_processors::structs::PaymentProcessorDTO,
+    reconciliation::SuspectProcessors,
+};
 
 pub(crate) type RedisQueueConnection = Pool<RedisConnectionManager>;
 
+const REAPER_BATCH_SIZE: usize = 100;
+const RELEASE_BATCH_SIZE: usize = 100;
+const STREAM_PAYLOAD_FIELD: &str = "payload";
+const STREAM_REAPER_CONSUMER: &str = "reaper";
+
+// Moves the next message into the processing list of consumer ARGV[2], stamps its visibility
+// deadline and records the consumer as its owner.
+static LIST_POP_SCRIPT: LazyLock<Script> = LazyLock::new(|| {
+    Script::new(
+        r"
+        local msg = redis.call('LMOVE', KEYS[1], KEYS[2], 'RIGHT', 'LEFT')
+        if msg then
+            local now = redis.call('TIME')
+            local deadline = now[1] * 1000 + math.floor(now[2] / 1000) + tonumber(ARGV[1])
+            redis.call('ZADD', KEYS[3], deadline, msg)
+            redis.call('HSET', KEYS[4], msg, ARGV[2])
+            redis.call('SADD', KEYS[5], ARGV[2])
+        end
+        return msg
+        ",
+    )
+});
+
+// Forgets an in-flight message and, when ARGV[2] is set, puts it back on the queue as ARGV[2].
+static LIST_SETTLE_SCRIPT: LazyLock<Script> = LazyLock::new(|| {
+    Script::new(
+        r"
+        redis.call('LREM', KEYS[2], 1, ARGV[1])
+        redis.call('ZREM', KEYS[3], ARGV[1])
+        redis.call('HDEL', KEYS[4], ARGV[1])
+        if ARGV[2] ~= '' then
+            redis.call('RPUSH', KEYS[1], ARGV[2])
+        end
+        return 1
+        ",
+    )
+});
+
+// Requeues messages whose visibility deadline has passed, wherever they are being held.
+// ARGV[2..] are the consumers known to the caller, whose processing lists follow the first
+// four keys in the same order. A message owned by a consumer that registered since is left
+// for the next run; consumers left holding nothing are forgotten.
+static LIST_REAP_SCRIPT: LazyLock<Script> = LazyLock::new(|| {
+    Script::new(
+        r"
+        local lists = {}
+        for i = 2, #ARGV do
+            lists[ARGV[i]] = KEYS[i + 3]
+        end
+        local now = redis.call('TIME')
+        local now_ms = now[1] * 1000 + math.floor(now[2] / 1000)
+        local expired = redis.call('ZRANGEBYSCORE', KEYS[2], '-inf', now_ms, 'LIMIT', 0, tonumber(ARGV[1]))
+        local requeued = 0
+        for _, msg in ipairs(expired) do
+            local owner = redis.call('HGET', KEYS[3], msg)
+            if not owner or lists[owner] then
+                if owner then
+                    redis.call('LREM', lists[owner], 1, msg)
+                    redis.call('HDEL', KEYS[3], msg)
+                end
+                redis.call('ZREM', KEYS[2], msg)
+                redis.call('RPUSH', KEYS[1], msg)
+                requeued = requeued + 1
+            end
+        end
+        for i = 2, #ARGV do
+            if redis.call('LLEN', KEYS[i + 3]) == 0 then
+                redis.call('SREM', KEYS[4], ARGV[i])
+            end
+        end
+        return requeued
+        ",
+    )
+});
+
+// Forgets an in-flight list message that can't be read and sets it aside in the quarantine list.
+static LIST_QUARANTINE_SCRIPT: LazyLock<Script> = LazyLock::new(|| {
+    Script::new(
+        r"
+        redis.call('LREM', KEYS[1], 1, ARGV[1])
+        redis.call('ZREM', KEYS[2], ARGV[1])
+        redis.call('HDEL', KEYS[3], ARGV[1])
+        redis.call('LPUSH', KEYS[4], ARGV[1])
+        return 1
+        ",
+    )
+});
+
+// Forgets an in-flight list message and parks its next attempt (ARGV[2]) in the delayed set
+// until ARGV[3] milliseconds from now.
+static LIST_RETRY_SCRIPT: LazyLock<Script> = LazyLock::new(|| {
+    Script::new(
+        r"
+        redis.call('LREM', KEYS[1], 1, ARGV[1])
+        redis.call('ZREM', KEYS[2], ARGV[1])
+        redis.call('HDEL', KEYS[3], ARGV[1])
+        local now = redis.call('TIME')
+        local due = now[1] * 1000 + math.floor(now[2] / 1000) + tonumber(ARGV[3])
+        redis.call('ZADD', KEYS[4], due, ARGV[2])
+        return 1
+        ",
+    )
+});
+
+// Acks and deletes stream entry ARGV[2] and parks its next attempt (ARGV[3]) in the delayed set
+// until ARGV[4] milliseconds from now.
+static STREAM_RETRY_SCRIPT: LazyLock<Script> = LazyLock::new(|| {
+    Script::new(
+        r"
+        redis.call('XACK', KEYS[1], ARGV[1], ARGV[2])
+        redis.call('XDEL', KEYS[1], ARGV[2])
+        local now = redis.call('TIME')
+        local due = now[1] * 1000 + math.floor(now[2] / 1000) + tonumber(ARGV[4])
+        redis.call('ZADD', KEYS[2], due, ARGV[3])
+        return 1
+        ",
+    )
+});
+
+// Moves delayed messages that are due onto the queue: the pop end of a list, or the end of a
+// stream when ARGV[2] names the payload field.
+static RELEASE_DUE_SCRIPT: LazyLock<Script> = LazyLock::new(|| {
+    Script::new(
+        r"
+        local now = redis.call('TIME')
+        local now_ms = now[1] * 1000 + math.floor(now[2] / 1000)
+        local due = redis.call('ZRANGEBYSCORE', KEYS[2], '-inf', now_ms, 'LIMIT', 0, tonumber(ARGV[1]))
+        for _, msg in ipairs(due) do
+            if ARGV[2] == '' then
+                redis.call('RPUSH', KEYS[1], msg)
+            else
+                redis.call('XADD', KEYS[1], '*', ARGV[2], msg)
+            end
+            redis.call('ZREM', KEYS[2], msg)
+        end
+        return #due
+        ",
+    )
+});
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum QueueBackend {
+    /// Redis lists with per-consumer processing lists.
+    List,
+    /// Redis Streams with a consumer group.
+    Stream,
+}
+
+impl FromStr for QueueBackend {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "list" => Ok(QueueBackend::List),
+            "stream" => Ok(QueueBackend::Stream),
+            _ => Err("expected list or stream".to_string()),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+enum DeliveryReceipt {
+    List {
+        processing_list: String,
+        raw: String,
+    },
+    Stream {
+        id: String,
+    },
+    /// Handed out by a queue that lives in this process; Redis has nothing to settle.
+    InProcess,
+}
+
+/// A payment as it waits in the queue, with how often it was tried before.
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct QueuedPayment {
+    pub payment: PaymentProcessorDTO,
+    /// Deliveries that ended without the payment being processed.
+    pub attempts: u32,
+    /// Processors that may have the payment already.
+    #[serde(default, skip_serializing_if = "SuspectProcessors::is_empty")]
+    pub suspects: SuspectProcessors,
+    /// When a delayed retry becomes due. `None` for payments that may go straight away.
+    pub eligible_at: Option<DateTime<Utc>>,
+    /// When the payment's deadline budget started running, if not when it was requested.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub budget_started_at: Option<DateTime<Utc>>,
+}
+
+impl QueuedPayment {
+    pub fn new(payment: PaymentProcessorDTO) -> Self {
+        Self {
+            payment,
+            attempts: 0,
+            suspects: SuspectProcessors::default(),
+            eligible_at: None,
+            budget_started_at: None,
+        }
+    }
+
+    /// When the payment's deadline budget started running. Every delivery spends from it.
+    pub fn budget_started_at(&self) -> DateTime<Utc> {
+        self.budget_started_at.unwrap_or(self.payment.requested_at)
+    }
+
+    /// The next attempt at this payment, due once `delay` has passed.
+    pub fn retry_after(&self, delay: Duration) -> Self {
+        Self {
+            attempts: self.attempts + 1,
+            eligible_at: Some(Utc::now() + delay),
+            ..*self
+        }
+    }
+
+    pub(crate) fn to_json(self) -> Result<String, bb8_redis::redis::RedisError> {
+        serde_json::to_string(&self).map_err(|e| {
+            bb8_redis::redis::RedisError::from((
+                bb8_redis::redis::ErrorKind::ParseError,
+                "Serialization error",
+                e.to_string(),
+            ))
+        })
+    }
+
+    /// Also reads the bare payments queued before attempts were counted.
+    fn from_json(raw: &str) -> Result<Self, bb8_redis::redis::RedisError> {
+        serde_json::from_str(raw)
+            .or_else(|_| serde_json::from_str(raw).map(Self::new))
+            .map_err(|e| {
+                bb8_redis::redis::RedisError::from((
+                    bb8_redis::redis::ErrorKind::ParseError,
+                    "Deserialization error",
+                    e.to_string(),
+                ))
+            })
+    }
+}
+
+/// A payment taken from the queue. It stays in flight until it is acked, nacked or retried
+/// later, or until its visibility timeout expires and the reaper requeues it.
+#[derive(Debug, Clone)]
+pub struct QueueDelivery {
+    pub payment: PaymentProcessorDTO,
+    /// Deliveries of this payment before this one that ended without it being processed.
+    pub attempts: u32,
+    /// Processors that may have the payment already. Whatever this holds when the delivery
+    /// is nacked or retried later goes back on the queue with the payment.
+    pub suspects: SuspectProcessors,
+    /// When the payment's deadline budget, shared by all its deliveries, started running.
+    pub budget_started_at: DateTime<Utc>,
+    receipt: DeliveryReceipt,
+}
+
+impl QueueDelivery {
+    pub(crate) fn in_process(queued: QueuedPayment) -> Self {
+        Self::new(queued, DeliveryReceipt::InProcess)
+    }
+
+    fn new(queued: QueuedPayment, receipt: DeliveryReceipt) -> Self {
+        Self {
+            payment: queued.payment,
+            attempts: queued.attempts,
+            suspects: queued.suspects,
+            budget_started_at: queued.budget_started_at(),
+            receipt,
+        }
+    }
+
+    pub(crate) fn queued(&self) -> QueuedPayment {
+        QueuedPayment {
+            payment: self.payment,
+            attempts: self.attempts,
+            suspects: self.suspects,
+            eligible_at: None,
+            budget_started_at: Some(self.budget_started_at),
+        }
+    }
+}
+
 #[derive(Debug, Clone)]
 pub struct RedisQueue {
     pool: RedisQueueConnection,
     collection_name: String,
+    backend: QueueBackend,
+    visibility_timeout_ms: u64,
 }
 
 impl RedisQueue {
-    pub fn new(pool: RedisQueueConnection) -> Self {
-        let collection_name =
-            std::env::var("REDIS_QUEUE_NAME").unwrap_or_else(|_| "payments_queue".to_string());
-
+    pub fn new(pool: RedisQueueConnection, config: &QueueConfig) -> Self {
         Self {
             pool,
-            collection_name,
+            collection_name: config.name.clone(),
+            backend: config.backend,
+            visibility_timeout_ms: config.visibility_timeout_ms,
+        }
+    }
+
+    /// Creates the consumer group when running on Redis Streams. Safe to call on every startup.
+    pub async fn init(&self) -> Result<(), bb8_redis::redis::RedisError> {
+        if self.backend != QueueBackend::Stream {
+            return Ok(());
+        }
+
+        let mut conn = self.connection().await?;
+        let created: Result<(), _> = AsyncCommands::xgroup_create_mkstream(
+            &mut *conn,
+            self.stream_key(),
+            self.group_name(),
+            "0",
+        )
+        .await;
+
+        match created {
+            Err(e) if e.code() != Some("BUSYGROUP") => Err(e),
+            _ => Ok(()),
         }
     }
 
-    pub async fn push(
+    pub async fn push(&self, payment: QueuedPayment) -> Result<(), bb8_redis::redis::RedisError> {
+        let mut conn = self.connection().await?;
+        let value = payment.to_json()?;
+
+        match self.backend {
+            QueueBackend::List => {
+                let _: () = AsyncCommands::lpush(&mut *conn, &self.collection_name, value).await?;
+            }
+            QueueBackend::Stream => {
+                let _: String = AsyncCommands::xadd(
+                    &mut *conn,
+                    self.stream_key(),
+                    "*",
+                    &[(STREAM_PAYLOAD_FIELD, value)],
+                )
+                .await?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Takes the next payment on behalf of `consumer` without removing it for good.
+    /// A message that can't be read is moved to the quarantine list and returned as an error,
+    /// rather than left in flight for the reaper to hand out again.
+    pub async fn pop(
         &self,
-        payment: PaymentProcessorDTO,
+        consumer: &str,
+    ) -> Result<Option<QueueDelivery>, bb8_redis::redis::RedisError> {
+        let mut conn = self.connection().await?;
+
+        let delivery = match self.backend {
+            QueueBackend::List => {
+                let processing_list = self.processing_list(consumer);
+                let raw: Option<String> = LIST_POP_SCRIPT
+                    .key(&self.collection_name)
+                    .key(&processing_list)
+                    .key(self.inflight_key())
+                    .key(self.owners_key())
+                    .key(self.consumers_key())
+                    .arg(self.visibility_timeout_ms)
+                    .arg(consumer)
+                    .invoke_async(&mut *conn)
+                    .await?;
+
+                raw.map(|raw| {
+                    let receipt = DeliveryReceipt::List {
+                        processing_list,
+                        raw: raw.clone(),
+                    };
+                    (receipt, raw)
+                })
+            }
+            QueueBackend::Stream => {
+                let options = StreamReadOptions::default()
+                    .group(self.group_name(), consumer)
+                    .count(1);
+                let reply: Option<StreamReadReply> = AsyncCommands::xread_options(
+                    &mut *conn,
+                    &[self.stream_key()],
+                    &[">"],
+                    &options,
+                )
+                .await?;
+
+                reply
+                    .and_then(|reply| reply.keys.into_iter().next())
+                    .and_then(|key| key.ids.into_iter().next())
+                    .and_then(|entry| {
+                        let raw: String = entry.get(STREAM_PAYLOAD_FIELD)?;
+                        let receipt = DeliveryReceipt::Stream { id: entry.id };
+                        Some((receipt, raw))
+                    })
+            }
+        };
+
+        let Some((receipt, raw)) = delivery else {
+            return Ok(None);
+        };
+
+        match QueuedPayment::from_json(&raw) {
+            Ok(queued) => Ok(Some(QueueDelivery::new(queued, receipt))),
+            Err(e) => {
+                self.quarantine(&mut conn, &receipt, &raw).await?;
+                Err(bb8_redis::redis::RedisError::from((
+                    bb8_redis::redis::ErrorKind::ParseError,
+                    "Quarantined unreadable payment",
+                    format!("{e}: {raw}"),
+                )))
+            }
+        }
+    }
+
+    async fn quarantine(
+        &self,
+        conn: &mut PooledConnection<'_, RedisConnectionManager>,
+        receipt: &DeliveryReceipt,
+        raw: &str,
     ) -> Result<(), bb8_redis::redis::RedisError> {
-        let mut conn = self.pool.get().await.map_err(|e| {
-            bb8_redis::redis::RedisError::from((
-                bb8_redis::redis::ErrorKind::IoError,
-                "bb8 pool error",
-                e.to_string(),
-            ))
-        })?;
+        match receipt {
+            DeliveryReceipt::List {
+                processing_list, ..
+            } => {
+                let _: i32 = LIST_QUARANTINE_SCRIPT
+                    .key(processing_list)
+                    .key(self.inflight_key())
+                    .key(self.owners_key())
+                    .key(self.quarantine_key())
+                    .arg(raw)
+                    .invoke_async(&mut **conn)
+                    .await?;
+            }
+            DeliveryReceipt::Stream { id } => {
+                let stream_key = self.stream_key();
+                let _: () = redis::pipe()
+                    .atomic()
+                    .lpush(self.quarantine_key(), raw)
+                    .ignore()
+                    .xack(&stream_key, self.group_name(), &[id])
+                    .ignore()
+                    .xdel(&stream_key, &[id])
+                    .ignore()
+                    .query_async(&mut **conn)
+                    .await?;
+            }
+            DeliveryReceipt::InProcess => {}
+        }
+        Ok(())
+    }
 
-        let value = serde_json::to_string(&payment).map_err(|e| {
-            bb8_redis::redis::RedisError::from((
-                bb8_redis::redis::ErrorKind::ParseError,
-                "Serialization error",
-                e.to_string(),
-            ))
-        })?;
+    /// Marks a delivery as done so it is never redelivered.
+    pub async fn ack(&self, delivery: &QueueDelivery) -> Result<(), bb8_redis::redis::RedisError> {
+        self.settle(delivery, false).await
+    }
+
+    /// Gives a delivery back to the queue for another consumer to pick up.
+    pub async fn nack(&self, delivery: &QueueDelivery) -> Result<(), bb8_redis::redis::RedisError> {
+        self.settle(delivery, true).await
+    }
+
+    /// Settles a delivery and parks the payment's next attempt until `delay` has passed.
+    pub async fn retry_later(
+        &self,
+        delivery: &QueueDelivery,
+        delay: Duration,
+    ) -> Result<(), bb8_redis::redis::RedisError> {
+        let mut conn = self.connection().await?;
+        let next_attempt = delivery.queued().retry_after(delay).to_json()?;
+        let delay_ms = delay.as_millis() as u64;
+
+        match &delivery.receipt {
+            DeliveryReceipt::List {
+                processing_list,
+                raw,
+            } => {
+                let _: i32 = LIST_RETRY_SCRIPT
+                    .key(processing_list)
+                    .key(self.inflight_key())
+                    .key(self.owners_key())
+                    .key(self.delayed_key())
+                    .arg(raw)
+                    .arg(next_attempt)
+                    .arg(delay_ms)
+                    .invoke_async(&mut *conn)
+                    .await?;
+            }
+            DeliveryReceipt::Stream { id } => {
+                let _: i32 = STREAM_RETRY_SCRIPT
+                    .key(self.stream_key())
+                    .key(self.delayed_key())
+                    .arg(self.group_name())
+                    .arg(id)
+                    .arg(next_attempt)
+                    .arg(delay_ms)
+                    .invoke_async(&mut *conn)
+                    .await?;
+            }
+            DeliveryReceipt::InProcess => {}
+        }
+        Ok(())
+    }
+
+    /// Moves parked retries whose delay has passed back onto the queue.
+    pub async fn release_due(&self) -> Result<usize, bb8_redis::redis::RedisError> {
+        let mut conn = self.connection().await?;
+        let (queue_key, payload_field) = self.push_target();
+
+        RELEASE_DUE_SCRIPT
+            .key(queue_key)
+            .key(self.delayed_key())
+            .arg(RELEASE_BATCH_SIZE)
+            .arg(payload_field)
+            .invoke_async(&mut *conn)
+            .await
+    }
+
+    /// Payments waiting to be popped, counting retries not yet due.
+    /// On Streams this also counts deliveries not yet settled.
+    pub async fn depth(&self) -> Result<usize, bb8_redis::redis::RedisError> {
+        let mut conn = self.connection().await?;
+
+        let mut pipeline = redis::pipe();
+        match self.backend {
+            QueueBackend::List => pipeline.llen(&self.collection_name),
+            QueueBackend::Stream => pipeline.xlen(self.stream_key()),
+        };
+        let (queued, delayed): (usize, usize) = pipeline
+            .zcard(self.delayed_key())
+            .query_async(&mut *conn)
+            .await?;
+        Ok(queued + delayed)
+    }
+
+    /// Requeues deliveries that were not settled within the visibility timeout.
+    pub async fn requeue_expired(&self) -> Result<usize, bb8_redis::redis::RedisError> {
+        let mut conn = self.connection().await?;
+
+        match self.backend {
+            QueueBackend::List => {
+                let consumers: Vec<String> =
+                    AsyncCommands::smembers(&mut *conn, self.consumers_key()).await?;
+                let mut invocation = LIST_REAP_SCRIPT.prepare_invoke();
+                invocation
+                    .key(&self.collection_name)
+                    .key(self.inflight_key())
+                    .key(self.owners_key())
+                    .key(self.consumers_key())
+                    .arg(REAPER_BATCH_SIZE);
+                for consumer in &consumers {
+                    invocation.key(self.processing_list(consumer)).arg(consumer);
+                }
+                let requeued: usize = invocation.invoke_async(&mut *conn).await?;
+                Ok(requeued)
+            }
+            QueueBackend::Stream => {
+                let stream_key = self.stream_key();
+                let group_name = self.group_name();
+                let reply: StreamAutoClaimReply = AsyncCommands::xautoclaim_options(
+                    &mut *conn,
+                    &stream_key,
+                    &group_name,
+                    STREAM_REAPER_CONSUMER,
+                    self.visibility_timeout_ms,
+                    "0-0",
+                    StreamAutoClaimOptions::default().count(REAPER_BATCH_SIZE),
+                )
+                .await?;
 
-        let _: () = AsyncCommands::lpush(&mut *conn, &self.collection_name, value).await?;
+                let mut pipeline = redis::pipe();
+                pipeline.atomic();
+                for entry in &reply.claimed {
+                    if let Some(raw) = entry.get::<String>(STREAM_PAYLOAD_FIELD) {
+                        pipeline
+                            .xadd(&stream_key, "*", &[(STREAM_PAYLOAD_FIELD, raw)])
+                            .ignore();
+                    }
+                    pipeline
+                        .xack(&stream_key, &group_name, &[&entry.id])
+                        .ignore()
+                        .xdel(&stream_key, &[&entry.id])
+                        .ignore();
+                }
+                let _: () = pipeline.query_async(&mut *conn).await?;
+                Ok(reply.claimed.len())
+            }
+        }
+    }
+
+    async fn settle(
+        &self,
+        delivery: &QueueDelivery,
+        requeue: bool,
+    ) -> Result<(), bb8_redis::redis::RedisError> {
+        let mut conn = self.connection().await?;
+        // Requeued as the delivery stands now, so suspects found on this delivery are kept.
+        let requeued = if requeue {
+            Some(delivery.queued().to_json()?)
+        } else {
+            None
+        };
+
+        match &delivery.receipt {
+            DeliveryReceipt::List {
+                processing_list,
+                raw,
+            } => {
+                let _: i32 = LIST_SETTLE_SCRIPT
+                    .key(&self.collection_name)
+                    .key(processing_list)
+                    .key(self.inflight_key())
+                    .key(self.owners_key())
+                    .arg(raw)
+                    .arg(requeued.unwrap_or_default())
+                    .invoke_async(&mut *conn)
+                    .await?;
+            }
+            DeliveryReceipt::Stream { id } => {
+                let stream_key = self.stream_key();
+                let mut pipeline = redis::pipe();
+                pipeline.atomic();
+                if let Some(requeued) = requeued {
+                    pipeline
+                        .xadd(&stream_key, "*", &[(STREAM_PAYLOAD_FIELD, requeued)])
+                        .ignore();
+                }
+                pipeline
+                    .xack(&stream_key, self.group_name(), &[id])
+                    .ignore()
+                    .xdel(&stream_key, &[id])
+                    .ignore();
+                let _: () = pipeline.query_async(&mut *conn).await?;
+            }
+            DeliveryReceipt::InProcess => {}
+        }
         Ok(())
     }
 
-    pub async fn pop(&self) -> Result<Option<PaymentProcessorDTO>, bb8_redis::redis::RedisError> {
-        let mut conn = self.pool.get().await.map_err(|e| {
+    async fn connection(
+        &self,
+    ) -> Result<PooledConnection<'_, RedisConnectionManager>, bb8_redis::redis::RedisError> {
+        self.pool.get().await.map_err(|e| {
             bb8_redis::redis::RedisError::from((
                 bb8_redis::redis::ErrorKind::IoError,
                 "bb8 pool error",
                 e.to_string(),
             ))
-        })?;
+        })
+    }
 
-        let value: Option<String> = AsyncCommands::rpop(&mut *conn, &self.collection_name, None).await?;
-        
-        if let Some(value) = value {
-            let payment: PaymentProcessorDTO = serde_json::from_str(&value).map_err(|e| {
-                bb8_redis::redis::RedisError::from((
-                    bb8_redis::redis::ErrorKind::ParseError,
-                    "Deserialization error",
-                    e.to_string(),
-                ))
-            })?;
-            Ok(Some(payment))
-        } else {
-            Ok(None)
+    /// The key payments are queued on, and the stream field that holds them; empty for a list.
+    pub(crate) fn push_target(&self) -> (String, &'static str) {
+        match self.backend {
+            QueueBackend::List => (self.collection_name.clone(), ""),
+            QueueBackend::Stream => (self.stream_key(), STREAM_PAYLOAD_FIELD),
         }
     }
 
+    fn processing_list(&self, consumer: &str) -> String {
+        format!("{}:processing:{}", self.collection_name, consumer)
+    }
+
+    fn inflight_key(&self) -> String {
+        format!("{}:inflight", self.collection_name)
+    }
 
+    /// Hash of in-flight message to the consumer holding it.
+    fn owners_key(&self) -> String {
+        format!("{}:owners", self.collection_name)
+    }
+
+    /// Set of the consumers that may hold in-flight messages.
+    fn consumers_key(&self) -> String {
+        format!("{}:consumers", self.collection_name)
+    }
+
+    fn delayed_key(&self) -> String {
+        format!("{}:delayed", self.collection_name)
+    }
+
+    /// Messages that could not be read, kept for someone to look at.
+    fn quarantine_key(&self) -> String {
+        format!("{}:quarantine", self.collection_name)
+    }
+
+    fn stream_key(&self) -> String {
+        format!("{}:stream", self.collection_name)
+    }
+
+    fn group_name(&self) -> String {
+        format!("{}:workers", self.collection_name)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use uuid::Uuid;
+
+    use super::*;
+    use crate::money::Money;
+
+    #[test]
+    fn queued_payments_use_camel_case_on_the_wire() {
+        let payment = PaymentProcessorDTO {
+            correlation_id: Uuid::from_u128(1),
+            amount: Money::from_cents(1990),
+            requested_at: "2025-07-01T12:00:00Z".parse().unwrap(),
+        };
+        let mut queued = QueuedPayment::new(payment).retry_after(Duration::ZERO);
+        queued.budget_started_at = Some(payment.requested_at);
+
+        let value: serde_json::Value = serde_json::from_str(&queued.to_json().unwrap()).unwrap();
+        let mut fields: Vec<&str> = value
+            .as_object()
+            .unwrap()
+            .keys()
+            .map(String::as_str)
+            .collect();
+        fields.sort_unstable();
+        assert_eq!(
+            fields,
+            ["attempts", "budgetStartedAt", "eligibleAt", "payment"]
+        );
+        assert_eq!(
+            value["payment"]["correlationId"],
+            Uuid::from_u128(1).to_string()
+        );
+
+        let read = QueuedPayment::from_json(&value.to_string()).unwrap();
+        assert_eq!(read.budget_started_at, queued.budget_started_at);
+        assert_eq!(read.eligible_at, queued.eligible_at);
+    }
 }
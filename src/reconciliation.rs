@@ -0,0 +1,201 @@
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    payment_processors::{
+        service::{PaymentProcessorServices, find_payment},
+        structs::{PaymentProcessorDTO, PaymentProcessorHealth},
+    },
+    service::PaymentDeadline,
+    structs::AppState,
+};
+
+/// The processors a payment's calls reached without a clear answer. They travel with the
+/// payment through the queue, so whichever instance takes it next checks with them before
+/// sending it anywhere again.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SuspectProcessors {
+    #[serde(default)]
+    pub default: bool,
+    #[serde(default)]
+    pub fallback: bool,
+}
+
+impl SuspectProcessors {
+    pub fn mark(&mut self, service: PaymentProcessorServices) {
+        match service {
+            PaymentProcessorServices::Default => self.default = true,
+            PaymentProcessorServices::Fallback => self.fallback = true,
+        }
+    }
+
+    /// Forgets `service`, once it said it doesn't have the payment.
+    pub fn clear(&mut self, service: PaymentProcessorServices) {
+        match service {
+            PaymentProcessorServices::Default => self.default = false,
+            PaymentProcessorServices::Fallback => self.fallback = false,
+        }
+    }
+
+    pub fn contains(&self, service: PaymentProcessorServices) -> bool {
+        match service {
+            PaymentProcessorServices::Default => self.default,
+            PaymentProcessorServices::Fallback => self.fallback,
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        !self.default && !self.fallback
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = PaymentProcessorServices> + use<> {
+        [
+            (self.default, PaymentProcessorServices::Default),
+            (self.fallback, PaymentProcessorServices::Fallback),
+        ]
+        .into_iter()
+        .filter_map(|(suspect, service)| suspect.then_some(service))
+    }
+}
+
+/// Asks the processors `payment` may have reached whether they have it, and returns the one
+/// that does. A processor that says it doesn't stops being a suspect; one whose lookup fails,
+/// or isn't reached before `deadline`, stays one, to be asked again.
+pub async fn reconcile(
+    state: &AppState,
+    payment: &PaymentProcessorDTO,
+    suspects: &mut SuspectProcessors,
+    health: &PaymentProcessorHealth,
+    deadline: PaymentDeadline,
+) -> Option<PaymentProcessorServices> {
+    let router = &state.payment_router;
+    for service in suspects.iter() {
+        if deadline.is_expired() {
+            break;
+        }
+        let check = match service {
+            PaymentProcessorServices::Default => &health.default,
+            PaymentProcessorServices::Fallback => &health.fallback,
+        };
+        match find_payment(
+            &state.http_client,
+            state.config.processors.url(service),
+            payment.correlation_id,
+            router.attempt_timeout(check).min(deadline.remaining()),
+        )
+        .await
+        {
+            Ok(true) => {
+                println!(
+                    "Payment {} turned out to be processed by {service}",
+                    payment.correlation_id
+                );
+                return Some(service);
+            }
+            Ok(false) => suspects.clear(service),
+            Err((status, message)) => eprintln!(
+                "Failed to look payment {} up on {service}: {status} {message}",
+                payment.correlation_id
+            ),
+        }
+    }
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, Instant};
+
+    use axum::http::StatusCode;
+    use uuid::Uuid;
+
+    use super::*;
+    use crate::{
+        money::Money, payment_processors::structs::PaymentProcessorHealthCheckDTO,
+        test_support::state_with_lookups as state,
+    };
+
+    use PaymentProcessorServices::{Default as D, Fallback as F};
+
+    fn health() -> PaymentProcessorHealth {
+        let check = PaymentProcessorHealthCheckDTO {
+            failing: false,
+            min_response_time: 10,
+        };
+        PaymentProcessorHealth {
+            default: check,
+            fallback: check,
+        }
+    }
+
+    fn payment() -> PaymentProcessorDTO {
+        PaymentProcessorDTO {
+            correlation_id: Uuid::from_u128(1),
+            amount: Money::from_cents(100),
+            requested_at: "2025-07-01T12:00:00Z".parse().unwrap(),
+        }
+    }
+
+    fn suspects(services: &[PaymentProcessorServices]) -> SuspectProcessors {
+        let mut suspects = SuspectProcessors::default();
+        for service in services {
+            suspects.mark(*service);
+        }
+        suspects
+    }
+
+    fn deadline() -> PaymentDeadline {
+        PaymentDeadline::new(Instant::now(), Duration::from_secs(5))
+    }
+
+    #[tokio::test]
+    async fn returns_the_processor_that_has_the_payment() {
+        let state = state(StatusCode::NOT_FOUND, StatusCode::OK).await;
+        let mut asked = suspects(&[D, F]);
+
+        let found = reconcile(&state, &payment(), &mut asked, &health(), deadline()).await;
+        assert_eq!(found, Some(F));
+    }
+
+    #[tokio::test]
+    async fn clears_processors_that_do_not_have_the_payment() {
+        let state = state(StatusCode::NOT_FOUND, StatusCode::NOT_FOUND).await;
+        let mut asked = suspects(&[D, F]);
+
+        let found = reconcile(&state, &payment(), &mut asked, &health(), deadline()).await;
+        assert_eq!(found, None);
+        assert!(asked.is_empty());
+    }
+
+    #[tokio::test]
+    async fn keeps_processors_whose_lookup_failed() {
+        let state = state(StatusCode::INTERNAL_SERVER_ERROR, StatusCode::NOT_FOUND).await;
+        let mut asked = suspects(&[D, F]);
+
+        let found = reconcile(&state, &payment(), &mut asked, &health(), deadline()).await;
+        assert_eq!(found, None);
+        assert_eq!(asked, suspects(&[D]));
+    }
+
+    #[tokio::test]
+    async fn asks_nobody_once_the_deadline_passed() {
+        let state = state(StatusCode::OK, StatusCode::OK).await;
+        let mut asked = suspects(&[D]);
+        let expired = PaymentDeadline::new(Instant::now(), Duration::ZERO);
+
+        let found = reconcile(&state, &payment(), &mut asked, &health(), expired).await;
+        assert_eq!(found, None);
+        assert_eq!(asked, suspects(&[D]));
+    }
+
+    #[test]
+    fn lists_each_suspect_processor_once() {
+        let mut suspects = SuspectProcessors::default();
+        assert!(suspects.is_empty());
+
+        suspects.mark(PaymentProcessorServices::Fallback);
+        suspects.mark(PaymentProcessorServices::Default);
+        suspects.mark(PaymentProcessorServices::Fallback);
+        assert!(!suspects.is_empty());
+        assert_eq!(suspects.iter().collect::<Vec<_>>(), [D, F]);
+    }
+}
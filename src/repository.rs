@@ -2,10 +2,12 @@ use std::error::Error;
 
 use crate::{
     db::{MemoryDatabase, PostgresDatabase},
+    money::Money,
     structs::{PaymentDatabaseEntry, PaymentsServiceSummary},
+    summary::BucketRange,
 };
 use redis::RedisError;
-use tokio_postgres::Row;
+use tokio_postgres::{Client, Row, Statement, types::Type};
 
 use chrono::{DateTime, Utc};
 
@@ -13,142 +15,192 @@ use crate::{
     db::PostgresPooledConnection, payment_processors, structs::PaymentsSummaryResponseDTO,
 };
 
-// const INSERT_QUERY: &str = "INSERT INTO transactions (correlation_id, processed_at, amount, service) VALUES ($1, $2, $3, $4)";
+const BULK_INSERT_QUERY: &str = "WITH inserted AS (INSERT INTO transactions (correlation_id, processed_at, amount, service) SELECT * FROM UNNEST($1::uuid[], $2::timestamptz[], $3::bigint[], $4::text[]) ON CONFLICT (correlation_id) DO NOTHING RETURNING 1) SELECT COUNT(*) FROM inserted";
+
+// Bounds the size of a single statement; the array parameters have no count limit.
+const INSERT_CHUNK_SIZE: usize = 50_000;
 
 const SUMMARY_QUERY: &str = "SELECT service, COUNT(*) as total_requests, CAST(COALESCE(SUM(amount), 0) as BIGINT) as total_amount FROM transactions WHERE ($1::timestamptz IS NULL OR processed_at >= $1) AND ($2::timestamptz IS NULL OR processed_at <= $2) GROUP BY service";
 
+/// Prepares `BULK_INSERT_QUERY` on `client`, for `insert_memory_payments` to run.
+pub(crate) async fn prepare_bulk_insert(
+    client: &Client,
+) -> Result<Statement, tokio_postgres::Error> {
+    client
+        .prepare_typed(
+            BULK_INSERT_QUERY,
+            &[
+                Type::UUID_ARRAY,
+                Type::TIMESTAMPTZ_ARRAY,
+                Type::INT8_ARRAY,
+                Type::TEXT_ARRAY,
+            ],
+        )
+        .await
+}
+
 fn extract_summary(rows: &[Row], service: &str) -> PaymentsServiceSummary {
     for row in rows {
         let row_service: String = row.get("service");
         if row_service == service {
             let total_requests: i64 = row.get("total_requests");
-            let total_amount_dec: i64 = row.get("total_amount");
-            let mut total_amount: f64 = total_amount_dec as f64; // Convert cents to dollars
-            total_amount /= 100.00; // Convert cents to dollars
+            let total_amount_cents: i64 = row.get("total_amount");
             return PaymentsServiceSummary {
                 total_requests: total_requests as u32,
-                total_amount,
+                total_amount: Money::from_cents(total_amount_cents),
             };
         }
     }
     PaymentsServiceSummary {
         total_requests: 0,
-        total_amount: 0.0,
+        total_amount: Money::ZERO,
     }
 }
 
-// fn extract_memory_summary(memory_payments: &[PaymentDatabaseEntry]) -> PaymentsSummaryResponseDTO {
-//     let mut default_total_requests = 0u32;
-//     let mut default_total_amount = 0f64;
-//     let mut fallback_total_requests = 0u32;
-//     let mut fallback_total_amount = 0f64;
-
-//     for entry in memory_payments {
-//         match entry.service {
-//             PaymentProcessorServices::Default => {
-//                 default_total_requests += 1;
-//                 default_total_amount += entry.amount;
-//             }
-//             PaymentProcessorServices::Fallback => {
-//                 fallback_total_requests += 1;
-//                 fallback_total_amount += entry.amount;
-//             }
-//             _ => {}
-//         }
-//     }
-
-//     PaymentsSummaryResponseDTO {
-//         default: PaymentsServiceSummary {
-//             total_requests: default_total_requests,
-//             total_amount: default_total_amount,
-//         },
-//         fallback: PaymentsServiceSummary {
-//             total_requests: fallback_total_requests,
-//             total_amount: fallback_total_amount,
-//         },
-//     }
-// }
-
 pub async fn save_processed_payment(
     mem_db: &MemoryDatabase,
     correlation_id: uuid::Uuid,
     date: DateTime<Utc>,
-    amount: f64,
+    amount: Money,
     service: payment_processors::service::PaymentProcessorServices,
 ) -> Result<(), RedisError> {
+    let entry = PaymentDatabaseEntry {
+        correlation_id,
+        requested_at: date,
+        amount,
+        service,
+    };
     mem_db
-        .insert(&format!(
-            "{}|{}|{}|{}",
-            correlation_id,
-            date.to_rfc3339(),
-            (amount * 100.0).round() as i64,
-            service.to_string()
-        ))
+        .insert(
+            &format!(
+                "{}|{}|{}|{}",
+                correlation_id,
+                date.to_rfc3339(),
+                amount.cents(),
+                service
+            ),
+            &entry,
+        )
         .await?;
     Ok(())
 }
 
-pub async fn get_payments_summary<'a>(
-    memory_database: &MemoryDatabase,
-    db: &PostgresDatabase,
+/// Same summary as `get_payments_summary`, summed from the per-second buckets in Redis.
+pub async fn get_memory_payments_summary(
+    mem_db: &MemoryDatabase,
     from: Option<DateTime<Utc>>,
     to: Option<DateTime<Utc>>,
-) -> Result<PaymentsSummaryResponseDTO, Box<dyn Error>> {
-    let result = memory_database
-        .pop_all()
-        .await
-        .map_err(|e| Box::new(e) as Box<dyn Error>)?;
-
-    let memory_payments: Vec<PaymentDatabaseEntry> = result
-        .into_iter()
-        .filter_map(|entry| {
-            let parts: Vec<&str> = entry.split('|').collect();
-            if parts.len() != 4 {
-                return None;
+) -> Result<PaymentsSummaryResponseDTO, RedisError> {
+    use payment_processors::service::PaymentProcessorServices;
+
+    let range = BucketRange::new(from, to);
+    let service_summary = |(total_requests, total_cents): (i64, i64)| PaymentsServiceSummary {
+        total_requests: total_requests as u32,
+        total_amount: Money::from_cents(total_cents),
+    };
+
+    let default = mem_db
+        .bucket_summary(PaymentProcessorServices::Default, &range)
+        .await?;
+    let fallback = mem_db
+        .bucket_summary(PaymentProcessorServices::Fallback, &range)
+        .await?;
+    Ok(PaymentsSummaryResponseDTO {
+        default: service_summary(default),
+        fallback: service_summary(fallback),
+    })
+}
+
+fn parse_memory_entry(entry: &str) -> Option<PaymentDatabaseEntry> {
+    use payment_processors::service::PaymentProcessorServices;
+
+    let parts: Vec<&str> = entry.split('|').collect();
+    if parts.len() != 4 {
+        return None;
+    }
+    let correlation_id = uuid::Uuid::parse_str(parts[0]).ok()?;
+    let requested_at = DateTime::parse_from_rfc3339(parts[1])
+        .ok()?
+        .with_timezone(&Utc);
+    let amount = Money::from_cents(parts[2].parse::<i64>().ok()?);
+    let service = match parts[3] {
+        "default" => PaymentProcessorServices::Default,
+        "fallback" => PaymentProcessorServices::Fallback,
+        _ => return None,
+    };
+    Some(PaymentDatabaseEntry {
+        correlation_id,
+        requested_at,
+        amount,
+        service,
+    })
+}
+
+/// Parses a batch of buffered memory entries. Entries that can't be read are logged and
+/// returned apart, for the caller to set aside rather than drop.
+pub fn parse_memory_entries(entries: &[String]) -> (Vec<PaymentDatabaseEntry>, Vec<String>) {
+    let mut payments = Vec::with_capacity(entries.len());
+    let mut rejected = Vec::new();
+    for entry in entries {
+        match parse_memory_entry(entry) {
+            Some(payment) => payments.push(payment),
+            None => {
+                eprintln!("Quarantining unreadable buffered payment: {entry:?}");
+                rejected.push(entry.clone());
             }
-            let correlation_id = uuid::Uuid::parse_str(parts[0]).ok()?;
-            let requested_at = DateTime::parse_from_rfc3339(parts[1])
-                .ok()?
-                .with_timezone(&Utc);
-            let amount_cents = parts[2].parse::<i64>().ok()?;
-            let amount = amount_cents as f64 / 100.0; // Convert cents to dollars
-            let service = payment_processors::service::PaymentProcessorServices::from(parts[3]);
-            Some(PaymentDatabaseEntry {
-                correlation_id,
-                requested_at,
-                amount,
-                service,
-            })
-        })
-        .collect();
+        }
+    }
+    (payments, rejected)
+}
 
+/// Writes a batch of buffered payments to Postgres, `INSERT_CHUNK_SIZE` rows per statement.
+/// Rows already stored are skipped. Returns how many rows were actually written.
+pub async fn insert_memory_payments(
+    db: &PostgresDatabase,
+    memory_payments: &[PaymentDatabaseEntry],
+) -> Result<u64, Box<dyn Error + Send + Sync>> {
+    if memory_payments.is_empty() {
+        return Ok(0);
+    }
 
+    let conn = db.pool.get().await?;
+
+    let mut rows_written = 0;
+    for chunk in memory_payments.chunks(INSERT_CHUNK_SIZE) {
+        let correlation_ids: Vec<uuid::Uuid> =
+            chunk.iter().map(|entry| entry.correlation_id).collect();
+        let processed_at: Vec<DateTime<Utc>> =
+            chunk.iter().map(|entry| entry.requested_at).collect();
+        let amounts: Vec<i64> = chunk.iter().map(|entry| entry.amount.cents()).collect();
+        let services: Vec<String> = chunk
+            .iter()
+            .map(|entry| entry.service.to_string())
+            .collect();
+
+        // Prepared once per connection, so a flush only binds and runs it: Postgres parses
+        // it once, and can keep reusing a cached plan instead of planning each batch.
+        let rows = conn
+            .query(
+                &conn.bulk_insert,
+                &[&correlation_ids, &processed_at, &amounts, &services],
+            )
+            .await?;
+        let inserted: i64 = rows.first().map(|row| row.get(0)).unwrap_or(0);
+        rows_written += inserted as u64;
+    }
+
+    Ok(rows_written)
+}
 
+pub async fn get_payments_summary(
+    db: &PostgresDatabase,
+    from: Option<DateTime<Utc>>,
+    to: Option<DateTime<Utc>>,
+) -> Result<PaymentsSummaryResponseDTO, Box<dyn Error>> {
     let conn = db.pool.get().await.map_err(|e| {
         Box::new(e) as Box<dyn Error>
     })?;
 
-    if !memory_payments.is_empty() {
-        
-        let mut query = String::from("INSERT INTO transactions (correlation_id, processed_at, amount, service) VALUES ");
-        let mut params: Vec<&(dyn tokio_postgres::types::ToSql + Sync)> = Vec::new();
-        let mut placeholders = Vec::new();
-
-
-        let amount_cents_vec: Vec<i64> = memory_payments.iter().map(|entry| (entry.amount * 100.0).round() as i64).collect();
-        let service_str_vec: Vec<String> = memory_payments.iter().map(|entry| entry.service.to_string()).collect();
-        for (i, entry) in memory_payments.iter().enumerate() {
-            let base = i * 4;
-            placeholders.push(format!("(${}, ${}, ${}, ${})", base + 1, base + 2, base + 3, base + 4));
-            params.push(&entry.correlation_id);
-            params.push(&entry.requested_at);
-            params.push(&amount_cents_vec[i]);
-            params.push(&service_str_vec[i]);
-        }
-        query.push_str(&placeholders.join(", "));
-        let _ = conn.execute(query.as_str(), &params).await?;
-    }
-
     let rows = conn.query(SUMMARY_QUERY, &[&from, &to]).await?;
 
     let summary = PaymentsSummaryResponseDTO {
@@ -164,3 +216,30 @@ pub async fn purge_payments<'a>(conn: PostgresPooledConnection<'a>) -> Result<u6
     let rows_affected = conn.execute(query, &[]).await?;
     Ok(rows_affected)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sets_aside_buffered_entries_it_cannot_read() {
+        let id = uuid::Uuid::from_u128(1);
+        let good = format!("{id}|2025-07-01T12:00:00+00:00|1990|fallback");
+        let entries = [
+            good.clone(),
+            format!("{id}|2025-07-01T12:00:00+00:00|1990"),
+            format!("{id}|yesterday|1990|default"),
+            format!("{id}|2025-07-01T12:00:00+00:00|19.90|default"),
+            format!("{id}|2025-07-01T12:00:00+00:00|1990|backup"),
+        ];
+
+        let (payments, rejected) = parse_memory_entries(&entries);
+        assert_eq!(payments.len(), 1);
+        assert_eq!(payments[0].amount, Money::from_cents(1990));
+        assert_eq!(
+            payments[0].service,
+            payment_processors::service::PaymentProcessorServices::Fallback
+        );
+        assert_eq!(rejected, &entries[1..]);
+    }
+}
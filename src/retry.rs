@@ -0,0 +1,74 @@
+use std::{
+    hash::{BuildHasher, RandomState},
+    time::{Duration, Instant},
+};
+
+use crate::config::QueueConfig;
+
+/// Exponential backoff with jitter. The retry after `n` failed attempts waits between half
+/// and all of `base * 2^n`, capped at `max`, so payments failing together spread out.
+#[derive(Debug, Clone, Copy)]
+pub struct Backoff {
+    base: Duration,
+    max: Duration,
+}
+
+impl Backoff {
+    pub fn new(base: Duration, max: Duration) -> Self {
+        Self { base, max }
+    }
+
+    pub fn from_config(config: &QueueConfig) -> Self {
+        Self::new(
+            Duration::from_millis(config.retry_base_delay_ms),
+            Duration::from_millis(config.retry_max_delay_ms),
+        )
+    }
+
+    /// The longest the retry after `attempts` failed attempts may wait.
+    pub fn ceiling(&self, attempts: u32) -> Duration {
+        self.base
+            .saturating_mul(2u32.saturating_pow(attempts))
+            .min(self.max)
+    }
+
+    pub fn delay(&self, attempts: u32) -> Duration {
+        let ceiling = self.ceiling(attempts);
+        ceiling / 2 + (ceiling / 2).mul_f64(random_fraction())
+    }
+}
+
+/// A fraction in `[0, 1]`. Every `RandomState` is keyed differently, which is random enough
+/// to spread retries without pulling in a crate for it.
+fn random_fraction() -> f64 {
+    RandomState::new().hash_one(Instant::now()) as f64 / u64::MAX as f64
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn doubles_up_to_the_cap_with_jitter() {
+        let backoff = Backoff::new(Duration::from_millis(50), Duration::from_secs(5));
+        let cases: &[(u32, u64)] = &[
+            (0, 50),
+            (1, 100),
+            (2, 200),
+            (6, 3_200),
+            (7, 5_000),
+            (40, 5_000),
+        ];
+        for (attempts, ceiling_ms) in cases {
+            let ceiling = Duration::from_millis(*ceiling_ms);
+            assert_eq!(backoff.ceiling(*attempts), ceiling, "attempts {attempts}");
+            for _ in 0..20 {
+                let delay = backoff.delay(*attempts);
+                assert!(
+                    ceiling / 2 <= delay && delay <= ceiling,
+                    "attempts {attempts}: {delay:?}"
+                );
+            }
+        }
+    }
+}
@@ -1,83 +1,340 @@
-use std::{env, sync::Arc};
+use std::time::{Duration, Instant};
+
+use chrono::{DateTime, Utc};
+use reqwest::StatusCode;
 
 use crate::{
-    db::MemoryDatabase,
-    error_handling::internal_error,
+    dead_letter::DeadLetter,
+    error_handling::{AppError, internal_error},
     payment_processors::{
         self,
-        structs::{PaymentProcessorDTO, PaymentProcessorHealth},
+        error::ProcessorError,
+        routing::PaymentRouter,
+        service::PaymentProcessorServices,
+        structs::{HealthSnapshot, PaymentProcessorDTO},
     },
-    queue::RedisQueue,
-    repository,
+    queue::QueueDelivery,
+    reconciliation::{SuspectProcessors, reconcile},
+    shutdown::InFlightGuard,
+    structs::{AppState, PaymentDatabaseEntry},
 };
-use axum::http::StatusCode;
 
 pub fn select_service(
-    payment_processors_health: &PaymentProcessorHealth,
+    router: &PaymentRouter,
+    health_snapshot: &HealthSnapshot,
 ) -> Option<payment_processors::service::PaymentProcessorServices> {
-    let max_response_time = env::var("PAYMENT_PROCESSOR_MAX_RESPONSE_TIME")
-        .ok()
-        .and_then(|s| s.parse::<i32>().ok())
-        .unwrap_or(payment_processors::structs::PAYMENT_PROCESSOR_MAX_RESPONSE_TIME);
-
-    if !payment_processors_health.default.failing
-        || payment_processors_health.default.min_response_time < max_response_time
-    {
-        Some(payment_processors::service::PaymentProcessorServices::Default)
-    } else if !payment_processors_health.fallback.failing
-        || payment_processors_health.fallback.min_response_time < max_response_time
-    {
-        Some(payment_processors::service::PaymentProcessorServices::Fallback)
-    } else {
-        None
+    router.route(health_snapshot).into_iter().next()
+}
+
+/// Time left for a payment, shared by every delivery, attempt and fallback.
+#[derive(Debug, Clone, Copy)]
+pub struct PaymentDeadline(Instant);
+
+impl PaymentDeadline {
+    pub fn new(started_at: Instant, budget: Duration) -> Self {
+        Self(started_at + budget)
+    }
+
+    /// The deadline of a budget that started at `started_at`, maybe on another instance.
+    pub fn since(started_at: DateTime<Utc>, budget: Duration) -> Self {
+        let spent = (Utc::now() - started_at).to_std().unwrap_or_default();
+        Self::new(Instant::now(), budget.saturating_sub(spent))
+    }
+
+    pub fn remaining(&self) -> Duration {
+        self.0.saturating_duration_since(Instant::now())
+    }
+
+    pub fn is_expired(&self) -> bool {
+        self.remaining().is_zero()
     }
 }
 
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum PaymentOutcome {
+    Processed(PaymentProcessorServices),
+    /// A processor turned the payment down for good. It is left to the caller to dead-letter.
+    Rejected(ProcessorError),
+    /// No processor took the payment. It is left to the caller to queue it again.
+    NotProcessed,
+    /// The deadline passed before a processor took the payment. It is left to the caller to
+    /// dead-letter, as retrying it would only spend more time it doesn't have.
+    DeadlineExceeded,
+}
+
+/// Sends `payload` to the processors in routing order. A processor whose call ends without a
+/// clear answer is added to `suspects`, which the caller keeps with the payment.
+/// While a processor is a suspect the payment goes to no other: sending it to the suspect
+/// again is safe, as the processor turns duplicates away, but another one would charge twice.
+/// `in_flight`, for a payment accepted over HTTP, hears of each processor before it is called.
 pub async fn process_payment(
-    memory_database: &MemoryDatabase,
-    http_client: &reqwest::Client,
-    process_queue: &RedisQueue,
-    payment_processors_health: Arc<tokio::sync::RwLock<PaymentProcessorHealth>>,
+    state: &AppState,
     payload: PaymentProcessorDTO,
-) -> Result<(StatusCode, String), (StatusCode, String)> {
-    let health_guard = payment_processors_health.read().await;
-    let service = select_service(&health_guard);
-
-    if service.is_none() {
-        process_queue.push(payload).await.map_err(internal_error)?;
-        Ok((
-            StatusCode::ACCEPTED,
-            "Payment queued for processing".to_string(),
-        ))
-    } else {
+    suspects: &mut SuspectProcessors,
+    deadline: PaymentDeadline,
+    in_flight: Option<&InFlightGuard>,
+) -> Result<PaymentOutcome, AppError> {
+    let router = &state.payment_router;
+    let health = router.health(&*state.processor_health.read().await);
+    let services = router.route_health(&health);
+
+    // A processor that may already have the payment is asked before anyone is sent it again.
+    if let Some(service) = reconcile(state, &payload, suspects, &health, deadline).await {
+        return record_processed(state, &payload, service).await;
+    }
+
+    let started_at = Instant::now();
+    let mut outcome = PaymentOutcome::NotProcessed;
+    for (attempt, service) in services.into_iter().enumerate() {
+        if !suspects.is_empty() && !suspects.contains(service) {
+            continue;
+        }
+        if deadline.is_expired() {
+            outcome = PaymentOutcome::DeadlineExceeded;
+            break;
+        }
+        if attempt > 0 && started_at.elapsed() > router.failover_budget {
+            break;
+        }
+
+        let Some(permit) = router.circuit_breaker.try_acquire(service) else {
+            continue;
+        };
+
+        let check = match service {
+            PaymentProcessorServices::Default => &health.default,
+            PaymentProcessorServices::Fallback => &health.fallback,
+        };
+        if let Some(in_flight) = in_flight {
+            in_flight.suspect(service);
+        }
         let response = payment_processors::service::process_transaction(
-            http_client,
+            &state.http_client,
+            state.config.processors.url(service),
             &payload,
-            payment_processors::service::PaymentProcessorServices::Default,
+            router.attempt_timeout(check).min(deadline.remaining()),
+            router.latency.processor(service),
         )
         .await;
 
+        let breaker_update = match &response {
+            Err(error) if error.is_processor_failure() => {
+                router.circuit_breaker.record_failure(permit).await
+            }
+            // Being told to slow down, or that this instance is misconfigured, says nothing
+            // about the processor's health either way.
+            Err(ProcessorError::RateLimited | ProcessorError::Configuration { .. }) => {
+                drop(permit);
+                Ok(())
+            }
+            // The processor answered; a rejected request says nothing about its health.
+            _ => router.circuit_breaker.record_success(permit).await,
+        };
+        if let Err(e) = breaker_update {
+            eprintln!("Failed to update circuit breaker: {e:?}");
+        }
+
         match response {
-            Ok(_res) => {
-                repository::save_processed_payment(
-                    memory_database,
+            Ok(()) | Err(ProcessorError::Duplicate) => {
+                return record_processed(state, &payload, service).await;
+            }
+            Err(
+                error @ ProcessorError::Permanent {
+                    status: StatusCode::UNPROCESSABLE_ENTITY,
+                    ..
+                },
+            ) => {
+                // A processor may answer 422 for a payment it already has: ask which it was.
+                match payment_processors::service::find_payment(
+                    &state.http_client,
+                    state.config.processors.url(service),
                     payload.correlation_id,
-                    payload.requested_at,
-                    payload.amount,
-                    service.unwrap(),
+                    router.attempt_timeout(check).min(deadline.remaining()),
                 )
                 .await
-                .map_err(internal_error)?;
-
-                Ok((StatusCode::OK, "Payment processed successfully".to_string()))
+                {
+                    Ok(true) => return record_processed(state, &payload, service).await,
+                    Ok(false) => return Ok(PaymentOutcome::Rejected(error)),
+                    // It may have the payment, so it is asked again on the next delivery.
+                    Err((status, message)) => {
+                        eprintln!(
+                            "Failed to look payment {} up on {service}: {status} {message}",
+                            payload.correlation_id
+                        );
+                        suspects.mark(service);
+                    }
+                }
             }
-            Err(_err) => {
-                process_queue.push(payload).await.map_err(internal_error)?;
-                Ok((
-                    StatusCode::ACCEPTED,
-                    "Payment queued for processing".to_string(),
-                ))
+            Err(error @ ProcessorError::Permanent { .. }) => {
+                return Ok(PaymentOutcome::Rejected(error));
             }
+            Err(ProcessorError::Configuration { status, message }) => eprintln!(
+                "{service} turned the request away with {status}, check its URL and credentials: {message}"
+            ),
+            Err(error) if error.is_ambiguous() => suspects.mark(service),
+            Err(_) => {}
+        }
+    }
+
+    if outcome == PaymentOutcome::DeadlineExceeded {
+        eprintln!(
+            "Payment {} ran out of its deadline budget",
+            payload.correlation_id
+        );
+    }
+    Ok(outcome)
+}
+
+/// Records `payload` as processed by `service`.
+async fn record_processed(
+    state: &AppState,
+    payload: &PaymentProcessorDTO,
+    service: PaymentProcessorServices,
+) -> Result<PaymentOutcome, AppError> {
+    state
+        .payments
+        .record(&PaymentDatabaseEntry {
+            correlation_id: payload.correlation_id,
+            requested_at: payload.requested_at,
+            amount: payload.amount,
+            service,
+        })
+        .await
+        .map_err(|e| internal_error(&*e))?;
+    Ok(PaymentOutcome::Processed(service))
+}
+
+/// Moves a payment to the dead letters. Returns whether they took it.
+pub async fn dead_letter(state: &AppState, dead_letter: &DeadLetter) -> bool {
+    match state.dead_letters.push(dead_letter).await {
+        Ok(()) => true,
+        Err(e) => {
+            eprintln!(
+                "Failed to dead-letter payment {}: {e:?}",
+                dead_letter.payment.correlation_id
+            );
+            false
+        }
+    }
+}
+
+/// Moves `delivery` to the dead letters, or back onto the queue if they can't take it.
+pub async fn dead_letter_delivery(
+    state: &AppState,
+    delivery: &QueueDelivery,
+    dead_letter: &DeadLetter,
+) {
+    let settled = if self::dead_letter(state, dead_letter).await {
+        state.queue.ack(delivery).await
+    } else {
+        state.queue.nack(delivery).await
+    };
+    if let Err(e) = settled {
+        eprintln!("Failed to settle payment: {e:?}");
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::http::StatusCode;
+    use uuid::Uuid;
+
+    use super::*;
+    use crate::{
+        config::CircuitBreakerConfig,
+        money::Money,
+        payment_processors::circuit_breaker::{CircuitBreaker, CircuitState},
+        test_support::state_with_processors as state,
+    };
+
+    use PaymentProcessorServices::{Default as D, Fallback as F};
+
+    const NOT_FOUND: StatusCode = StatusCode::NOT_FOUND;
+
+    fn payment() -> PaymentProcessorDTO {
+        PaymentProcessorDTO {
+            correlation_id: Uuid::from_u128(1),
+            amount: Money::from_cents(100),
+            requested_at: Utc::now(),
         }
     }
+
+    async fn process(state: &AppState) -> PaymentOutcome {
+        let deadline = PaymentDeadline::new(Instant::now(), Duration::from_secs(5));
+        let mut suspects = SuspectProcessors::default();
+        process_payment(state, payment(), &mut suspects, deadline, None)
+            .await
+            .unwrap()
+    }
+
+    /// A breaker that trips on the first failure.
+    fn breaker(state: &mut AppState) -> CircuitBreaker {
+        let breaker = CircuitBreaker::local(&CircuitBreakerConfig {
+            failure_threshold: 1,
+            open_duration_ms: 1,
+            ..CircuitBreakerConfig::default()
+        });
+        state.payment_router.circuit_breaker = breaker.clone();
+        breaker
+    }
+
+    #[tokio::test]
+    async fn records_payments_the_processor_says_it_already_has() {
+        let conflict = state(
+            (StatusCode::CONFLICT, NOT_FOUND),
+            (StatusCode::OK, NOT_FOUND),
+        )
+        .await;
+        assert_eq!(process(&conflict).await, PaymentOutcome::Processed(D));
+
+        // A 422 for a payment the lookup then finds.
+        let unprocessable = state(
+            (StatusCode::UNPROCESSABLE_ENTITY, StatusCode::OK),
+            (StatusCode::OK, NOT_FOUND),
+        )
+        .await;
+        assert_eq!(process(&unprocessable).await, PaymentOutcome::Processed(D));
+    }
+
+    #[tokio::test]
+    async fn rejects_payments_the_processor_turns_away_and_does_not_have() {
+        let state = state(
+            (StatusCode::UNPROCESSABLE_ENTITY, NOT_FOUND),
+            (StatusCode::OK, NOT_FOUND),
+        )
+        .await;
+        assert!(matches!(
+            process(&state).await,
+            PaymentOutcome::Rejected(ProcessorError::Permanent { .. })
+        ));
+    }
+
+    #[tokio::test]
+    async fn misconfigured_processor_is_skipped_without_tripping_its_circuit() {
+        let mut state = state(
+            (StatusCode::UNAUTHORIZED, NOT_FOUND),
+            (StatusCode::OK, NOT_FOUND),
+        )
+        .await;
+        let breaker = breaker(&mut state);
+
+        assert_eq!(process(&state).await, PaymentOutcome::Processed(F));
+        assert_eq!(breaker.state(D), CircuitState::Closed);
+    }
+
+    #[tokio::test]
+    async fn rate_limited_probe_leaves_the_circuit_half_open() {
+        let rate_limited = (StatusCode::TOO_MANY_REQUESTS, NOT_FOUND);
+        let mut state = state(rate_limited, rate_limited).await;
+        let breaker = breaker(&mut state);
+        breaker
+            .record_failure(breaker.try_acquire(D).unwrap())
+            .await
+            .unwrap();
+        tokio::time::sleep(Duration::from_millis(5)).await;
+
+        assert_eq!(process(&state).await, PaymentOutcome::NotProcessed);
+        assert_eq!(breaker.state(D), CircuitState::HalfOpen);
+        assert!(breaker.try_acquire(D).is_some());
+    }
 }
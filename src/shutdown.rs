@@ -0,0 +1,145 @@
+use std::{
+    sync::{
+        Arc,
+        atomic::{AtomicBool, Ordering},
+    },
+    time::Duration,
+};
+
+use dashmap::DashMap;
+use tokio::{
+    signal::unix::{SignalKind, signal},
+    time::Instant,
+};
+use uuid::Uuid;
+
+use crate::{
+    payment_processors::{service::PaymentProcessorServices, structs::PaymentProcessorDTO},
+    queue::QueuedPayment,
+};
+
+const DRAIN_POLL: Duration = Duration::from_millis(10);
+
+/// Set once the process has been asked to stop. Workers check it between payments.
+#[derive(Debug, Clone, Default)]
+pub struct Shutdown {
+    triggered: Arc<AtomicBool>,
+}
+
+impl Shutdown {
+    pub fn trigger(&self) {
+        self.triggered.store(true, Ordering::Relaxed);
+    }
+
+    pub fn is_triggered(&self) -> bool {
+        self.triggered.load(Ordering::Relaxed)
+    }
+
+    /// Waits for SIGTERM or SIGINT, then triggers the shutdown.
+    pub async fn on_signal(self) {
+        let mut terminate = signal(SignalKind::terminate()).expect("failed to listen for SIGTERM");
+        tokio::select! {
+            _ = terminate.recv() => eprintln!("Received SIGTERM"),
+            _ = tokio::signal::ctrl_c() => eprintln!("Received SIGINT"),
+        }
+        self.trigger();
+    }
+}
+
+/// Payments accepted over HTTP whose processing task hasn't finished yet, as they would be
+/// queued again if it never does.
+#[derive(Debug, Default)]
+pub struct InFlightPayments {
+    payments: DashMap<Uuid, QueuedPayment>,
+}
+
+impl InFlightPayments {
+    /// Tracks `payment` until the returned guard is finished.
+    pub fn track(self: &Arc<Self>, payment: PaymentProcessorDTO) -> InFlightGuard {
+        self.payments
+            .insert(payment.correlation_id, QueuedPayment::new(payment));
+        InFlightGuard {
+            in_flight: self.clone(),
+            correlation_id: payment.correlation_id,
+        }
+    }
+
+    /// Waits until every tracked payment finished, or `deadline` passes.
+    /// Returns whether all of them finished.
+    pub async fn drain(&self, deadline: Instant) -> bool {
+        while !self.payments.is_empty() {
+            if Instant::now() >= deadline {
+                return false;
+            }
+            tokio::time::sleep(DRAIN_POLL).await;
+        }
+        true
+    }
+
+    /// Stops tracking the payments still in flight and returns them.
+    pub fn take_all(&self) -> Vec<QueuedPayment> {
+        let correlation_ids: Vec<Uuid> = self.payments.iter().map(|entry| *entry.key()).collect();
+        correlation_ids
+            .into_iter()
+            .filter_map(|correlation_id| self.payments.remove(&correlation_id))
+            .map(|(_, payment)| payment)
+            .collect()
+    }
+}
+
+/// Keeps a payment tracked until `finish` is called. A task that is aborted or panics never
+/// gets there, so its payment stays tracked for the shutdown to requeue.
+pub struct InFlightGuard {
+    in_flight: Arc<InFlightPayments>,
+    correlation_id: Uuid,
+}
+
+impl InFlightGuard {
+    pub fn finish(self) {
+        self.in_flight.payments.remove(&self.correlation_id);
+    }
+
+    /// Notes that `service` may get the payment, so it is asked before the payment is sent
+    /// anywhere else should this task never finish.
+    pub fn suspect(&self, service: PaymentProcessorServices) {
+        if let Some(mut queued) = self.in_flight.payments.get_mut(&self.correlation_id) {
+            queued.suspects.mark(service);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::money::Money;
+
+    fn payment(id: u128) -> PaymentProcessorDTO {
+        PaymentProcessorDTO {
+            correlation_id: Uuid::from_u128(id),
+            amount: Money::from_cents(100),
+            requested_at: "2025-07-01T12:00:00Z".parse().unwrap(),
+        }
+    }
+
+    #[tokio::test]
+    async fn hands_back_payments_still_in_flight_at_the_deadline() {
+        let in_flight = Arc::new(InFlightPayments::default());
+        let finished = in_flight.track(payment(1));
+        let stuck = in_flight.track(payment(2));
+        stuck.suspect(PaymentProcessorServices::Fallback);
+        finished.finish();
+        // Dropped without finishing, as when its task is aborted mid-call.
+        drop(stuck);
+
+        let deadline = Instant::now() + Duration::from_millis(30);
+        assert!(!in_flight.drain(deadline).await);
+        let unfinished = in_flight.take_all();
+        assert_eq!(unfinished.len(), 1);
+        assert_eq!(unfinished[0].payment.correlation_id, Uuid::from_u128(2));
+        assert_eq!(
+            unfinished[0].suspects.iter().collect::<Vec<_>>(),
+            [PaymentProcessorServices::Fallback]
+        );
+        assert!(in_flight.drain(Instant::now()).await);
+    }
+}
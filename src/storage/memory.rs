@@ -0,0 +1,600 @@
+use std::{
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use async_trait::async_trait;
+use chrono::{DateTime, Utc};
+use dashmap::{DashMap, mapref::entry::Entry};
+use tokio::sync::{broadcast, mpsc};
+use uuid::Uuid;
+
+use crate::{
+    config::{Config, IdempotencyConfig},
+    dead_letter::DeadLetter,
+    health::ProcessorHealthState,
+    money::Money,
+    payment_processors::{
+        circuit_breaker::CircuitBreaker, service::PaymentProcessorServices, structs::HealthSnapshot,
+    },
+    queue::{QueueDelivery, QueuedPayment},
+    storage::{
+        DeadLetterStore, HealthBus, LeaderLease, PaymentStore, Storage, StorageError, WorkQueue,
+    },
+    structs::{PaymentDatabaseEntry, PaymentsServiceSummary, PaymentsSummaryResponseDTO},
+};
+
+const HEALTH_BUS_CAPACITY: usize = 16;
+
+/// Payments kept in a map for the life of the process.
+#[derive(Debug)]
+pub struct InMemoryPaymentStore {
+    /// When each claim expires, so they are kept as long as the Redis idempotency keys.
+    claims: DashMap<Uuid, Instant>,
+    claim_ttl: Duration,
+    /// When expired claims were last swept out of `claims`.
+    swept_at: Mutex<Instant>,
+    payments: DashMap<Uuid, PaymentDatabaseEntry>,
+}
+
+impl InMemoryPaymentStore {
+    pub fn new(claim_ttl: Duration) -> Self {
+        Self {
+            claims: DashMap::new(),
+            claim_ttl,
+            swept_at: Mutex::new(Instant::now()),
+            payments: DashMap::new(),
+        }
+    }
+
+    /// Drops the expired claims, at most once per TTL so claiming stays cheap.
+    fn sweep_expired_claims(&self, now: Instant) {
+        let Ok(mut swept_at) = self.swept_at.try_lock() else {
+            return;
+        };
+        if now.duration_since(*swept_at) >= self.claim_ttl {
+            *swept_at = now;
+            self.claims.retain(|_, expires_at| *expires_at > now);
+        }
+    }
+}
+
+impl Default for InMemoryPaymentStore {
+    fn default() -> Self {
+        Self::new(Duration::from_secs(
+            IdempotencyConfig::default().ttl_seconds,
+        ))
+    }
+}
+
+#[async_trait]
+impl PaymentStore for InMemoryPaymentStore {
+    async fn claim(&self, correlation_id: Uuid) -> Result<bool, StorageError> {
+        let now = Instant::now();
+        self.sweep_expired_claims(now);
+        match self.claims.entry(correlation_id) {
+            Entry::Occupied(mut claim) => {
+                if *claim.get() > now {
+                    return Ok(false);
+                }
+                claim.insert(now + self.claim_ttl);
+            }
+            Entry::Vacant(claim) => {
+                claim.insert(now + self.claim_ttl);
+            }
+        }
+        Ok(true)
+    }
+
+    async fn release(&self, correlation_id: Uuid) -> Result<(), StorageError> {
+        self.claims.remove(&correlation_id);
+        Ok(())
+    }
+
+    async fn record(&self, entry: &PaymentDatabaseEntry) -> Result<(), StorageError> {
+        self.payments
+            .entry(entry.correlation_id)
+            .or_insert_with(|| entry.clone());
+        Ok(())
+    }
+
+    async fn summary(
+        &self,
+        from: Option<DateTime<Utc>>,
+        to: Option<DateTime<Utc>>,
+    ) -> Result<PaymentsSummaryResponseDTO, StorageError> {
+        let mut summary = PaymentsSummaryResponseDTO {
+            default: PaymentsServiceSummary {
+                total_requests: 0,
+                total_amount: Money::ZERO,
+            },
+            fallback: PaymentsServiceSummary {
+                total_requests: 0,
+                total_amount: Money::ZERO,
+            },
+        };
+
+        for payment in self.payments.iter() {
+            if from.is_some_and(|from| payment.requested_at < from)
+                || to.is_some_and(|to| payment.requested_at > to)
+            {
+                continue;
+            }
+            let service_summary = match payment.service {
+                PaymentProcessorServices::Default => &mut summary.default,
+                PaymentProcessorServices::Fallback => &mut summary.fallback,
+            };
+            service_summary.total_requests += 1;
+            service_summary.total_amount += payment.amount;
+        }
+        Ok(summary)
+    }
+
+    async fn purge(&self) -> Result<u64, StorageError> {
+        let purged = self.payments.len() as u64;
+        self.payments.clear();
+        self.claims.clear();
+        Ok(purged)
+    }
+}
+
+/// An unbounded channel. Deliveries can't outlive the process, so there is nothing to reap.
+#[derive(Debug)]
+pub struct InMemoryQueue {
+    sender: mpsc::UnboundedSender<QueuedPayment>,
+    receiver: Mutex<mpsc::UnboundedReceiver<QueuedPayment>>,
+    /// Retries waiting for their delay to pass, with when it does.
+    delayed: Mutex<Vec<(Instant, QueuedPayment)>>,
+}
+
+impl Default for InMemoryQueue {
+    fn default() -> Self {
+        let (sender, receiver) = mpsc::unbounded_channel();
+        Self {
+            sender,
+            receiver: Mutex::new(receiver),
+            delayed: Mutex::new(Vec::new()),
+        }
+    }
+}
+
+#[async_trait]
+impl WorkQueue for InMemoryQueue {
+    async fn push(&self, payment: QueuedPayment) -> Result<(), StorageError> {
+        self.sender.send(payment)?;
+        Ok(())
+    }
+
+    async fn pop(&self, _consumer: &str) -> Result<Option<QueueDelivery>, StorageError> {
+        let queued = self
+            .receiver
+            .lock()
+            .map_err(|e| e.to_string())?
+            .try_recv()
+            .ok();
+        Ok(queued.map(QueueDelivery::in_process))
+    }
+
+    async fn ack(&self, _delivery: &QueueDelivery) -> Result<(), StorageError> {
+        Ok(())
+    }
+
+    async fn nack(&self, delivery: &QueueDelivery) -> Result<(), StorageError> {
+        self.sender.send(delivery.queued())?;
+        Ok(())
+    }
+
+    async fn retry_later(
+        &self,
+        delivery: &QueueDelivery,
+        delay: Duration,
+    ) -> Result<(), StorageError> {
+        let next_attempt = delivery.queued().retry_after(delay);
+        self.delayed
+            .lock()
+            .map_err(|e| e.to_string())?
+            .push((Instant::now() + delay, next_attempt));
+        Ok(())
+    }
+
+    async fn release_due(&self) -> Result<usize, StorageError> {
+        let now = Instant::now();
+        let mut delayed = self.delayed.lock().map_err(|e| e.to_string())?;
+        let mut released = 0;
+        for (_, queued) in delayed.extract_if(.., |(due, _)| *due <= now) {
+            self.sender.send(queued)?;
+            released += 1;
+        }
+        Ok(released)
+    }
+
+    async fn requeue_expired(&self) -> Result<usize, StorageError> {
+        Ok(0)
+    }
+
+    async fn depth(&self) -> Result<usize, StorageError> {
+        let queued = self.receiver.lock().map_err(|e| e.to_string())?.len();
+        let delayed = self.delayed.lock().map_err(|e| e.to_string())?.len();
+        Ok(queued + delayed)
+    }
+}
+
+#[derive(Debug)]
+pub struct InMemoryDeadLetters {
+    dead_letters: DashMap<Uuid, DeadLetter>,
+    /// Where replayed payments go.
+    queue: Arc<InMemoryQueue>,
+}
+
+impl InMemoryDeadLetters {
+    pub fn new(queue: Arc<InMemoryQueue>) -> Self {
+        Self {
+            dead_letters: DashMap::new(),
+            queue,
+        }
+    }
+}
+
+#[async_trait]
+impl DeadLetterStore for InMemoryDeadLetters {
+    async fn push(&self, dead_letter: &DeadLetter) -> Result<(), StorageError> {
+        self.dead_letters
+            .insert(dead_letter.payment.correlation_id, dead_letter.clone());
+        Ok(())
+    }
+
+    async fn list(&self, offset: usize, limit: usize) -> Result<Vec<DeadLetter>, StorageError> {
+        let mut dead_letters: Vec<DeadLetter> = self
+            .dead_letters
+            .iter()
+            .map(|dead_letter| dead_letter.clone())
+            .collect();
+        dead_letters.sort_by_key(|dead_letter| dead_letter.dead_lettered_at);
+        Ok(dead_letters.into_iter().skip(offset).take(limit).collect())
+    }
+
+    async fn get(&self, correlation_id: Uuid) -> Result<Option<DeadLetter>, StorageError> {
+        Ok(self
+            .dead_letters
+            .get(&correlation_id)
+            .map(|dead_letter| dead_letter.clone()))
+    }
+
+    async fn replay(&self, correlation_id: Uuid) -> Result<bool, StorageError> {
+        let Some((_, dead_letter)) = self.dead_letters.remove(&correlation_id) else {
+            return Ok(false);
+        };
+        self.queue.push(dead_letter.queued()).await?;
+        Ok(true)
+    }
+
+    async fn purge(&self) -> Result<u64, StorageError> {
+        let purged = self.dead_letters.len() as u64;
+        self.dead_letters.clear();
+        Ok(purged)
+    }
+}
+
+#[derive(Debug)]
+pub struct InMemoryHealthBus {
+    sender: broadcast::Sender<HealthSnapshot>,
+    latest: Mutex<Option<HealthSnapshot>>,
+}
+
+impl Default for InMemoryHealthBus {
+    fn default() -> Self {
+        let (sender, _) = broadcast::channel(HEALTH_BUS_CAPACITY);
+        Self {
+            sender,
+            latest: Mutex::new(None),
+        }
+    }
+}
+
+#[async_trait]
+impl HealthBus for InMemoryHealthBus {
+    async fn publish(&self, snapshot: &HealthSnapshot) -> Result<(), StorageError> {
+        *self.latest.lock().unwrap_or_else(|e| e.into_inner()) = Some(snapshot.clone());
+        // Nobody listening is fine: the poller applies its own snapshots.
+        let _ = self.sender.send(snapshot.clone());
+        Ok(())
+    }
+
+    async fn latest(&self) -> Result<Option<HealthSnapshot>, StorageError> {
+        Ok(self
+            .latest
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .clone())
+    }
+
+    async fn listen(&self, processor_health: Arc<ProcessorHealthState>) {
+        let mut receiver = self.sender.subscribe();
+        loop {
+            match receiver.recv().await {
+                Ok(snapshot) => {
+                    processor_health.apply(snapshot).await;
+                }
+                Err(broadcast::error::RecvError::Lagged(_)) => continue,
+                Err(broadcast::error::RecvError::Closed) => return,
+            }
+        }
+    }
+}
+
+/// With nobody to share it with, this instance always holds the lease.
+#[derive(Debug, Default)]
+pub struct LocalLeaderLease;
+
+#[async_trait]
+impl LeaderLease for LocalLeaderLease {
+    async fn acquire(&self, _holder: &str) -> Result<bool, StorageError> {
+        Ok(true)
+    }
+
+    async fn holder(&self) -> Result<Option<String>, StorageError> {
+        Ok(None)
+    }
+
+    async fn take_over(&self, _holder: &str, _stale: &str) -> Result<bool, StorageError> {
+        Ok(true)
+    }
+
+    async fn release(&self, _holder: &str) -> Result<(), StorageError> {
+        Ok(())
+    }
+}
+
+pub fn connect(config: &Config) -> Storage {
+    let queue = Arc::new(InMemoryQueue::default());
+    Storage {
+        payments: Arc::new(InMemoryPaymentStore::new(Duration::from_secs(
+            config.idempotency.ttl_seconds,
+        ))),
+        queue: queue.clone(),
+        dead_letters: Arc::new(InMemoryDeadLetters::new(queue)),
+        health_bus: Arc::new(InMemoryHealthBus::default()),
+        leader_lease: Arc::new(LocalLeaderLease),
+        circuit_breaker: CircuitBreaker::local(&config.circuit_breaker),
+        flusher: None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        payment_processors::structs::PaymentProcessorDTO, reconciliation::SuspectProcessors,
+    };
+
+    fn payment(
+        id: u128,
+        at: &str,
+        cents: i64,
+        service: PaymentProcessorServices,
+    ) -> PaymentDatabaseEntry {
+        PaymentDatabaseEntry {
+            correlation_id: Uuid::from_u128(id),
+            requested_at: at.parse().unwrap(),
+            amount: Money::from_cents(cents),
+            service,
+        }
+    }
+
+    #[tokio::test]
+    async fn payment_store_counts_each_payment_once_within_range() {
+        use PaymentProcessorServices::{Default as D, Fallback as F};
+        let store = InMemoryPaymentStore::default();
+        assert!(store.claim(Uuid::from_u128(1)).await.unwrap());
+        assert!(!store.claim(Uuid::from_u128(1)).await.unwrap());
+
+        for entry in [
+            payment(1, "2025-07-01T12:00:00.000Z", 1990, D),
+            payment(1, "2025-07-01T12:00:00.000Z", 1990, D),
+            payment(2, "2025-07-01T12:00:00.500Z", 1000, F),
+            payment(3, "2025-07-01T12:00:01.000Z", 500, D),
+        ] {
+            store.record(&entry).await.unwrap();
+        }
+
+        let all = store.summary(None, None).await.unwrap();
+        assert_eq!(
+            (all.default.total_requests, all.default.total_amount.cents()),
+            (2, 2490)
+        );
+        assert_eq!(
+            (
+                all.fallback.total_requests,
+                all.fallback.total_amount.cents()
+            ),
+            (1, 1000)
+        );
+
+        let from = "2025-07-01T12:00:00.500Z".parse().ok();
+        let to = "2025-07-01T12:00:00.999Z".parse().ok();
+        let window = store.summary(from, to).await.unwrap();
+        assert_eq!(window.default.total_requests, 0);
+        assert_eq!(window.fallback.total_requests, 1);
+
+        assert_eq!(store.purge().await.unwrap(), 3);
+    }
+
+    #[tokio::test]
+    async fn payment_store_claims_expire_and_are_purged() {
+        let store = InMemoryPaymentStore::new(Duration::from_millis(20));
+        assert!(store.claim(Uuid::from_u128(1)).await.unwrap());
+        assert!(!store.claim(Uuid::from_u128(1)).await.unwrap());
+        tokio::time::sleep(Duration::from_millis(30)).await;
+        assert!(store.claim(Uuid::from_u128(1)).await.unwrap());
+        assert!(store.claim(Uuid::from_u128(2)).await.unwrap());
+
+        // Claiming sweeps out the claims that expired meanwhile.
+        tokio::time::sleep(Duration::from_millis(30)).await;
+        store.claim(Uuid::from_u128(3)).await.unwrap();
+        assert_eq!(store.claims.len(), 1);
+
+        store.purge().await.unwrap();
+        assert!(store.claims.is_empty());
+        assert!(store.claim(Uuid::from_u128(3)).await.unwrap());
+    }
+
+    #[tokio::test]
+    async fn queue_redelivers_nacked_payments() {
+        let queue = InMemoryQueue::default();
+        let entry = payment(
+            7,
+            "2025-07-01T12:00:00Z",
+            100,
+            PaymentProcessorServices::Default,
+        );
+        queue
+            .push(QueuedPayment::new(PaymentProcessorDTO {
+                correlation_id: entry.correlation_id,
+                amount: entry.amount,
+                requested_at: entry.requested_at,
+            }))
+            .await
+            .unwrap();
+
+        assert_eq!(queue.depth().await.unwrap(), 1);
+        let delivery = queue.pop("worker").await.unwrap().unwrap();
+        assert!(queue.pop("worker").await.unwrap().is_none());
+        assert_eq!(queue.depth().await.unwrap(), 0);
+        queue.nack(&delivery).await.unwrap();
+        let redelivery = queue.pop("worker").await.unwrap().unwrap();
+        assert_eq!(redelivery.payment.correlation_id, entry.correlation_id);
+        queue.ack(&redelivery).await.unwrap();
+        assert!(queue.pop("worker").await.unwrap().is_none());
+    }
+
+    #[tokio::test]
+    async fn queue_holds_retries_until_they_are_due() {
+        let queue = InMemoryQueue::default();
+        for id in [1, 2] {
+            let entry = payment(
+                id,
+                "2025-07-01T12:00:00Z",
+                100,
+                PaymentProcessorServices::Default,
+            );
+            queue
+                .push(QueuedPayment::new(PaymentProcessorDTO {
+                    correlation_id: entry.correlation_id,
+                    amount: entry.amount,
+                    requested_at: entry.requested_at,
+                }))
+                .await
+                .unwrap();
+        }
+
+        let mut due = queue.pop("worker").await.unwrap().unwrap();
+        let later = queue.pop("worker").await.unwrap().unwrap();
+        assert_eq!(due.attempts, 0);
+        due.suspects.mark(PaymentProcessorServices::Fallback);
+        queue.retry_later(&due, Duration::ZERO).await.unwrap();
+        queue
+            .retry_later(&later, Duration::from_secs(60))
+            .await
+            .unwrap();
+        assert_eq!(queue.depth().await.unwrap(), 2);
+        assert!(queue.pop("worker").await.unwrap().is_none());
+
+        assert_eq!(queue.release_due().await.unwrap(), 1);
+        let retry = queue.pop("worker").await.unwrap().unwrap();
+        assert_eq!(
+            (retry.payment.correlation_id, retry.attempts),
+            (due.payment.correlation_id, 1)
+        );
+        assert_eq!(
+            retry.suspects.iter().collect::<Vec<_>>(),
+            [PaymentProcessorServices::Fallback]
+        );
+        assert_eq!(retry.budget_started_at, due.budget_started_at);
+        assert!(queue.pop("worker").await.unwrap().is_none());
+        assert_eq!(queue.depth().await.unwrap(), 1);
+    }
+
+    #[tokio::test]
+    async fn dead_letters_are_replayed_once() {
+        let queue = Arc::new(InMemoryQueue::default());
+        let dead_letters = InMemoryDeadLetters::new(queue.clone());
+        let entry = payment(
+            3,
+            "2025-07-01T12:00:00Z",
+            100,
+            PaymentProcessorServices::Default,
+        );
+        let payment = PaymentProcessorDTO {
+            correlation_id: entry.correlation_id,
+            amount: entry.amount,
+            requested_at: entry.requested_at,
+        };
+        let mut suspects = SuspectProcessors::default();
+        suspects.mark(PaymentProcessorServices::Default);
+        dead_letters
+            .push(&DeadLetter::new(
+                payment,
+                suspects,
+                100,
+                503,
+                "No processor took the payment".to_string(),
+            ))
+            .await
+            .unwrap();
+        assert_eq!(
+            dead_letters
+                .list(usize::MAX, usize::MAX)
+                .await
+                .unwrap()
+                .len(),
+            0
+        );
+
+        assert!(dead_letters.replay(entry.correlation_id).await.unwrap());
+        assert!(!dead_letters.replay(entry.correlation_id).await.unwrap());
+        assert!(dead_letters.list(0, 10).await.unwrap().is_empty());
+        let delivery = queue.pop("worker").await.unwrap().unwrap();
+        assert_eq!(
+            (delivery.payment.correlation_id, delivery.attempts),
+            (entry.correlation_id, 0)
+        );
+        assert_eq!(delivery.suspects, suspects);
+        // A replay starts the deadline budget over.
+        assert!(delivery.budget_started_at > entry.requested_at);
+        assert!(queue.pop("worker").await.unwrap().is_none());
+    }
+
+    #[tokio::test]
+    async fn health_bus_caches_the_latest_snapshot() {
+        use crate::payment_processors::structs::{
+            PaymentProcessorHealth, PaymentProcessorHealthCheckDTO,
+        };
+
+        let bus = InMemoryHealthBus::default();
+        assert!(bus.latest().await.unwrap().is_none());
+
+        let check = |failing| PaymentProcessorHealthCheckDTO {
+            failing,
+            min_response_time: 10,
+        };
+        let snapshot = HealthSnapshot {
+            health: PaymentProcessorHealth {
+                default: check(true),
+                fallback: check(false),
+            },
+            observed_at: Utc::now(),
+            source: "api1".to_string(),
+            sequence: 1,
+            observed: Default::default(),
+        };
+        bus.publish(&snapshot).await.unwrap();
+
+        let latest = bus.latest().await.unwrap().unwrap();
+        assert_eq!(
+            (latest.sequence, latest.observed_at),
+            (snapshot.sequence, snapshot.observed_at)
+        );
+        assert!(latest.health.default.failing);
+        assert!(!latest.health.fallback.failing);
+    }
+}
@@ -0,0 +1,200 @@
+use std::{error::Error, str::FromStr, sync::Arc, time::Duration};
+
+use async_trait::async_trait;
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use uuid::Uuid;
+
+use crate::{
+    config::Config,
+    dead_letter::DeadLetter,
+    flusher::PaymentFlusher,
+    health::ProcessorHealthState,
+    ledger::client::SocketPaymentStore,
+    payment_processors::{circuit_breaker::CircuitBreaker, structs::HealthSnapshot},
+    queue::{QueueDelivery, QueuedPayment},
+    structs::{PaymentDatabaseEntry, PaymentsSummaryResponseDTO},
+    summary::SummaryConsistency,
+};
+
+pub mod memory;
+pub mod redis;
+
+pub type StorageError = Box<dyn Error + Send + Sync>;
+
+const REPLAY_BATCH_SIZE: usize = 100;
+
+/// Where accepted and processed payments are kept.
+#[async_trait]
+pub trait PaymentStore: Send + Sync {
+    /// Marks a payment as accepted. Returns `false` when it already was.
+    async fn claim(&self, correlation_id: Uuid) -> Result<bool, StorageError>;
+
+    /// Gives a claim back, for a payment that was claimed but could never be queued.
+    async fn release(&self, correlation_id: Uuid) -> Result<(), StorageError>;
+
+    /// Records a payment a processor accepted. Recording the same payment twice counts it once.
+    async fn record(&self, entry: &PaymentDatabaseEntry) -> Result<(), StorageError>;
+
+    async fn summary(
+        &self,
+        from: Option<DateTime<Utc>>,
+        to: Option<DateTime<Utc>>,
+    ) -> Result<PaymentsSummaryResponseDTO, StorageError>;
+
+    /// Drops every recorded payment. Returns how many were dropped.
+    async fn purge(&self) -> Result<u64, StorageError>;
+
+    /// Compares the summary across the store's backends, or `None` when it only has one.
+    async fn check_consistency(
+        &self,
+        _from: Option<DateTime<Utc>>,
+        _to: Option<DateTime<Utc>>,
+    ) -> Result<Option<SummaryConsistency>, StorageError> {
+        Ok(None)
+    }
+}
+
+/// Payments waiting for a processor. A popped payment stays in flight until it is settled.
+#[async_trait]
+pub trait WorkQueue: Send + Sync {
+    async fn push(&self, payment: QueuedPayment) -> Result<(), StorageError>;
+
+    async fn pop(&self, consumer: &str) -> Result<Option<QueueDelivery>, StorageError>;
+
+    /// Marks a delivery as done so it is never redelivered.
+    async fn ack(&self, delivery: &QueueDelivery) -> Result<(), StorageError>;
+
+    /// Gives a delivery back to the queue for another consumer to pick up.
+    async fn nack(&self, delivery: &QueueDelivery) -> Result<(), StorageError>;
+
+    /// Settles a delivery and queues the payment again once `delay` has passed,
+    /// counting one more attempt.
+    async fn retry_later(
+        &self,
+        delivery: &QueueDelivery,
+        delay: Duration,
+    ) -> Result<(), StorageError>;
+
+    /// Moves retries whose delay has passed back onto the queue.
+    async fn release_due(&self) -> Result<usize, StorageError>;
+
+    /// Requeues deliveries whose consumer went away without settling them.
+    async fn requeue_expired(&self) -> Result<usize, StorageError>;
+
+    /// How many payments are waiting to be popped, counting retries not yet due.
+    async fn depth(&self) -> Result<usize, StorageError>;
+}
+
+/// Payments the workers gave up on, kept aside until they are replayed or purged.
+#[async_trait]
+pub trait DeadLetterStore: Send + Sync {
+    async fn push(&self, dead_letter: &DeadLetter) -> Result<(), StorageError>;
+
+    /// Lists dead letters, oldest first.
+    async fn list(&self, offset: usize, limit: usize) -> Result<Vec<DeadLetter>, StorageError>;
+
+    async fn get(&self, correlation_id: Uuid) -> Result<Option<DeadLetter>, StorageError>;
+
+    async fn purge(&self) -> Result<u64, StorageError>;
+
+    /// Sends a dead letter back to the work queue and removes it, in one step, so a crash
+    /// can't leave it both queued and dead-lettered. Returns `false` if it was not there.
+    async fn replay(&self, correlation_id: Uuid) -> Result<bool, StorageError>;
+
+    async fn replay_all(&self) -> Result<u64, StorageError> {
+        let mut replayed = 0;
+        loop {
+            let batch = self.list(0, REPLAY_BATCH_SIZE).await?;
+            if batch.is_empty() {
+                return Ok(replayed);
+            }
+
+            for dead_letter in batch {
+                if self.replay(dead_letter.payment.correlation_id).await? {
+                    replayed += 1;
+                }
+            }
+        }
+    }
+}
+
+/// Carries the health poller's snapshots to every instance.
+#[async_trait]
+pub trait HealthBus: Send + Sync {
+    /// Caches `snapshot` as the latest health check and broadcasts it.
+    async fn publish(&self, snapshot: &HealthSnapshot) -> Result<(), StorageError>;
+
+    /// The latest health check any instance published, if there was one.
+    async fn latest(&self) -> Result<Option<HealthSnapshot>, StorageError>;
+
+    /// Applies every published snapshot to `processor_health`. Only returns if the bus goes away.
+    async fn listen(&self, processor_health: Arc<ProcessorHealthState>);
+}
+
+/// Decides which instance polls processor health on behalf of the others.
+#[async_trait]
+pub trait LeaderLease: Send + Sync {
+    /// Takes the lease if nobody holds it, or renews it if `holder` does.
+    /// Returns whether `holder` holds it now.
+    async fn acquire(&self, holder: &str) -> Result<bool, StorageError>;
+
+    /// Who holds the lease, if anyone.
+    async fn holder(&self) -> Result<Option<String>, StorageError>;
+
+    /// Takes the lease from `stale`, an instance that stopped publishing, unless it changed
+    /// hands since. Returns whether `holder` holds it now.
+    async fn take_over(&self, holder: &str, stale: &str) -> Result<bool, StorageError>;
+
+    /// Gives the lease up if `holder` holds it, so another instance can take over right away.
+    async fn release(&self, holder: &str) -> Result<(), StorageError>;
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum StorageBackend {
+    /// Redis for shared state and Postgres for the ledger, so several instances can cooperate.
+    Redis,
+    /// Everything in this process. Needs no external services, but can't be shared.
+    Memory,
+    /// Payments in a store process reached over a Unix socket, everything else in Redis.
+    Socket,
+}
+
+impl FromStr for StorageBackend {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "redis" => Ok(StorageBackend::Redis),
+            "memory" => Ok(StorageBackend::Memory),
+            "socket" => Ok(StorageBackend::Socket),
+            _ => Err("expected redis, memory or socket".to_string()),
+        }
+    }
+}
+
+/// Every backend-specific piece the service runs on.
+#[derive(Clone)]
+pub struct Storage {
+    pub payments: Arc<dyn PaymentStore>,
+    pub queue: Arc<dyn WorkQueue>,
+    pub dead_letters: Arc<dyn DeadLetterStore>,
+    pub health_bus: Arc<dyn HealthBus>,
+    pub leader_lease: Arc<dyn LeaderLease>,
+    pub circuit_breaker: CircuitBreaker,
+    /// Moves buffered payments into Postgres, when there is a Postgres.
+    pub flusher: Option<PaymentFlusher>,
+}
+
+pub async fn connect(config: &Config) -> Storage {
+    match config.storage.backend {
+        StorageBackend::Redis => redis::connect(config).await,
+        StorageBackend::Memory => memory::connect(config),
+        StorageBackend::Socket => {
+            println!("Using the store at {}", config.store.socket_path);
+            let payments = Arc::new(SocketPaymentStore::new(&config.store));
+            redis::connect_with_payments(config, payments).await
+        }
+    }
+}
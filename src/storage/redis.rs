@@ -0,0 +1,291 @@
+use std::{sync::Arc, time::Duration};
+
+use async_trait::async_trait;
+use bb8_postgres::PostgresConnectionManager;
+use bb8_redis::RedisConnectionManager;
+use chrono::{DateTime, Utc};
+use tokio_postgres::NoTls;
+use uuid::Uuid;
+
+use crate::{
+    config::Config,
+    db::{MemoryDatabase, MemoryDatabaseConnection, PostgresDatabase, PreparedConnectionManager},
+    dead_letter::{DeadLetter, DeadLetterQueue},
+    flusher::PaymentFlusher,
+    health::ProcessorHealthState,
+    idempotency::IdempotencyStore,
+    leader::RedisLeaderLease,
+    payment_processors::{circuit_breaker::CircuitBreaker, structs::HealthSnapshot},
+    pubsub::HealthCheckChannel,
+    queue::{QueueDelivery, QueuedPayment, RedisQueue},
+    repository,
+    storage::{
+        DeadLetterStore, HealthBus, LeaderLease, PaymentStore, Storage, StorageError, WorkQueue,
+    },
+    structs::{PaymentDatabaseEntry, PaymentsSummaryResponseDTO},
+    summary::{SummaryBackend, SummaryConsistency},
+};
+
+/// Processed payments buffered in Redis and flushed to Postgres, with per-second summary
+/// buckets kept in Redis alongside. `summary_backend` picks which one answers summaries.
+#[derive(Debug, Clone)]
+pub struct RedisPaymentStore {
+    database: PostgresDatabase,
+    memory_database: MemoryDatabase,
+    flusher: PaymentFlusher,
+    idempotency: IdempotencyStore,
+    summary_backend: SummaryBackend,
+}
+
+impl RedisPaymentStore {
+    async fn postgres_summary(
+        &self,
+        from: Option<DateTime<Utc>>,
+        to: Option<DateTime<Utc>>,
+    ) -> Result<PaymentsSummaryResponseDTO, StorageError> {
+        self.flusher.flush_for_read().await?;
+        repository::get_payments_summary(&self.database, from, to)
+            .await
+            .map_err(|e| e.to_string().into())
+    }
+}
+
+#[async_trait]
+impl PaymentStore for RedisPaymentStore {
+    async fn claim(&self, correlation_id: Uuid) -> Result<bool, StorageError> {
+        Ok(self.idempotency.claim(correlation_id).await?)
+    }
+
+    async fn release(&self, correlation_id: Uuid) -> Result<(), StorageError> {
+        Ok(self.idempotency.release(correlation_id).await?)
+    }
+
+    async fn record(&self, entry: &PaymentDatabaseEntry) -> Result<(), StorageError> {
+        repository::save_processed_payment(
+            &self.memory_database,
+            entry.correlation_id,
+            entry.requested_at,
+            entry.amount,
+            entry.service,
+        )
+        .await?;
+        Ok(())
+    }
+
+    async fn summary(
+        &self,
+        from: Option<DateTime<Utc>>,
+        to: Option<DateTime<Utc>>,
+    ) -> Result<PaymentsSummaryResponseDTO, StorageError> {
+        match self.summary_backend {
+            SummaryBackend::Postgres => self.postgres_summary(from, to).await,
+            SummaryBackend::Redis => {
+                Ok(
+                    repository::get_memory_payments_summary(&self.memory_database, from, to)
+                        .await?,
+                )
+            }
+        }
+    }
+
+    async fn purge(&self) -> Result<u64, StorageError> {
+        // Empty the buffer first, so nothing is flushed into Postgres after it was purged.
+        self.memory_database.purge_buffer().await?;
+        let conn = self.database.pool.get().await?;
+        let rows_affected = repository::purge_payments(conn)
+            .await
+            .map_err(|e| e.to_string())?;
+        self.memory_database.purge_buckets().await?;
+        self.idempotency.purge().await?;
+        Ok(rows_affected)
+    }
+
+    async fn check_consistency(
+        &self,
+        from: Option<DateTime<Utc>>,
+        to: Option<DateTime<Utc>>,
+    ) -> Result<Option<SummaryConsistency>, StorageError> {
+        let postgres = self.postgres_summary(from, to).await?;
+        let redis =
+            repository::get_memory_payments_summary(&self.memory_database, from, to).await?;
+        Ok(Some(SummaryConsistency::new(postgres, redis)))
+    }
+}
+
+#[async_trait]
+impl WorkQueue for RedisQueue {
+    async fn push(&self, payment: QueuedPayment) -> Result<(), StorageError> {
+        Ok(RedisQueue::push(self, payment).await?)
+    }
+
+    async fn pop(&self, consumer: &str) -> Result<Option<QueueDelivery>, StorageError> {
+        Ok(RedisQueue::pop(self, consumer).await?)
+    }
+
+    async fn ack(&self, delivery: &QueueDelivery) -> Result<(), StorageError> {
+        Ok(RedisQueue::ack(self, delivery).await?)
+    }
+
+    async fn nack(&self, delivery: &QueueDelivery) -> Result<(), StorageError> {
+        Ok(RedisQueue::nack(self, delivery).await?)
+    }
+
+    async fn retry_later(
+        &self,
+        delivery: &QueueDelivery,
+        delay: Duration,
+    ) -> Result<(), StorageError> {
+        Ok(RedisQueue::retry_later(self, delivery, delay).await?)
+    }
+
+    async fn release_due(&self) -> Result<usize, StorageError> {
+        Ok(RedisQueue::release_due(self).await?)
+    }
+
+    async fn requeue_expired(&self) -> Result<usize, StorageError> {
+        Ok(RedisQueue::requeue_expired(self).await?)
+    }
+
+    async fn depth(&self) -> Result<usize, StorageError> {
+        Ok(RedisQueue::depth(self).await?)
+    }
+}
+
+#[async_trait]
+impl DeadLetterStore for DeadLetterQueue {
+    async fn push(&self, dead_letter: &DeadLetter) -> Result<(), StorageError> {
+        Ok(DeadLetterQueue::push(self, dead_letter).await?)
+    }
+
+    async fn list(&self, offset: usize, limit: usize) -> Result<Vec<DeadLetter>, StorageError> {
+        Ok(DeadLetterQueue::list(self, offset, limit).await?)
+    }
+
+    async fn get(&self, correlation_id: Uuid) -> Result<Option<DeadLetter>, StorageError> {
+        Ok(DeadLetterQueue::get(self, correlation_id).await?)
+    }
+
+    async fn replay(&self, correlation_id: Uuid) -> Result<bool, StorageError> {
+        Ok(DeadLetterQueue::replay(self, correlation_id).await?)
+    }
+
+    async fn purge(&self) -> Result<u64, StorageError> {
+        Ok(DeadLetterQueue::purge(self).await?)
+    }
+}
+
+#[async_trait]
+impl HealthBus for HealthCheckChannel {
+    async fn publish(&self, snapshot: &HealthSnapshot) -> Result<(), StorageError> {
+        Ok(self.update(snapshot).await?)
+    }
+
+    async fn latest(&self) -> Result<Option<HealthSnapshot>, StorageError> {
+        Ok(HealthCheckChannel::latest(self).await?)
+    }
+
+    async fn listen(&self, processor_health: Arc<ProcessorHealthState>) {
+        HealthCheckChannel::listen(self, processor_health).await
+    }
+}
+
+#[async_trait]
+impl LeaderLease for RedisLeaderLease {
+    async fn acquire(&self, holder: &str) -> Result<bool, StorageError> {
+        Ok(RedisLeaderLease::acquire(self, holder).await?)
+    }
+
+    async fn holder(&self) -> Result<Option<String>, StorageError> {
+        Ok(RedisLeaderLease::holder(self).await?)
+    }
+
+    async fn take_over(&self, holder: &str, stale: &str) -> Result<bool, StorageError> {
+        Ok(RedisLeaderLease::take_over(self, holder, stale).await?)
+    }
+
+    async fn release(&self, holder: &str) -> Result<(), StorageError> {
+        Ok(RedisLeaderLease::release(self, holder).await?)
+    }
+}
+
+/// Connects to the Postgres at `storage.database_url` and the Redis at
+/// `storage.memory_database_url`.
+pub async fn connect(config: &Config) -> Storage {
+    println!("Starting Postgres Connection Pool");
+    let manager =
+        PostgresConnectionManager::new_from_stringlike(&config.storage.database_url, NoTls)
+            .unwrap();
+    let pool = bb8::Pool::builder()
+        .build(PreparedConnectionManager::new(manager))
+        .await
+        .unwrap();
+    let database = PostgresDatabase::new(pool);
+
+    let memory_pool = connect_redis(config).await;
+    let memory_database = MemoryDatabase::new(
+        memory_pool.clone(),
+        &config.memory_database,
+        &config.hostname,
+    );
+    let flusher = PaymentFlusher::new(memory_database.clone(), database.clone(), &config.flusher);
+
+    let payments = RedisPaymentStore {
+        database,
+        memory_database,
+        flusher: flusher.clone(),
+        idempotency: IdempotencyStore::new(memory_pool.clone(), &config.idempotency),
+        summary_backend: config.storage.summary_backend,
+    };
+    let mut storage = shared_storage(config, memory_pool, Arc::new(payments)).await;
+    storage.flusher = Some(flusher);
+    storage
+}
+
+/// Keeps the queue, dead letters, health bus and circuit breaker in Redis,
+/// and payments in `payments`.
+pub async fn connect_with_payments(config: &Config, payments: Arc<dyn PaymentStore>) -> Storage {
+    let memory_pool = connect_redis(config).await;
+    shared_storage(config, memory_pool, payments).await
+}
+
+async fn connect_redis(config: &Config) -> MemoryDatabaseConnection {
+    println!("Starting Redis Connection Pool");
+    let memory_manager =
+        RedisConnectionManager::new(config.storage.memory_database_url.as_str()).unwrap();
+    bb8::Pool::builder()
+        .min_idle(10)
+        .max_size(32)
+        .build(memory_manager)
+        .await
+        .unwrap()
+}
+
+async fn shared_storage(
+    config: &Config,
+    memory_pool: MemoryDatabaseConnection,
+    payments: Arc<dyn PaymentStore>,
+) -> Storage {
+    println!("Starting Channel");
+    let channel_client =
+        ::redis::Client::open(config.storage.memory_database_url.as_str()).unwrap();
+    let health_check_channel =
+        HealthCheckChannel::new(memory_pool.clone(), channel_client, &config.health_check);
+
+    let leader_lease = RedisLeaderLease::new(memory_pool.clone(), &config.health_check);
+    let dead_letters = DeadLetterQueue::new(memory_pool.clone(), &config.queue);
+    let circuit_breaker = CircuitBreaker::new(memory_pool.clone(), &config.circuit_breaker);
+
+    println!("Starting DLQ");
+    let redis_queue = RedisQueue::new(memory_pool, &config.queue);
+    redis_queue.init().await.unwrap();
+
+    Storage {
+        payments,
+        queue: Arc::new(redis_queue),
+        dead_letters: Arc::new(dead_letters),
+        health_bus: Arc::new(health_check_channel),
+        leader_lease: Arc::new(leader_lease),
+        circuit_breaker,
+        flusher: None,
+    }
+}
@@ -2,24 +2,23 @@ use std::sync::Arc;
 
 use chrono::{DateTime, Utc};
 use serde::{Deserialize, Serialize};
-use tokio::sync::RwLock;
 use uuid::Uuid;
 
-use crate::payment_processors;
+use crate::{money::Money, payment_processors};
 
 #[derive(Debug, Clone, Deserialize)]
 pub struct PaymentDTO {
     #[serde(rename = "correlationId")]
     pub correlation_id: Uuid,
-    pub amount: f64,
+    pub amount: Money,
 }
 
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, PartialEq)]
 pub struct PaymentDatabaseEntry {
     pub correlation_id: Uuid,
     pub requested_at: DateTime<Utc>,
-    pub amount: f64,
+    pub amount: Money,
     pub service: payment_processors::service::PaymentProcessorServices,
 }
 
@@ -30,13 +29,19 @@ pub struct PaymentSummaryQuery {
     pub to: Option<DateTime<Utc>>,
 }
 
-#[derive(Clone, Serialize, Debug)]
+#[derive(Debug, Clone, Deserialize)]
+pub struct DeadLetterQuery {
+    pub offset: Option<usize>,
+    pub limit: Option<usize>,
+}
+
+#[derive(Clone, Serialize, Debug, PartialEq, Eq)]
 pub struct PaymentsServiceSummary {
     pub total_requests: u32,
-    pub total_amount: f64,
+    pub total_amount: Money,
 }
 
-#[derive(Debug, Clone, Serialize)]
+#[derive(Debug, Clone, Serialize, PartialEq, Eq)]
 pub struct PaymentsSummaryResponseDTO {
     pub default: PaymentsServiceSummary,
     pub fallback: PaymentsServiceSummary,
@@ -46,7 +51,7 @@ impl From<PaymentDTO> for payment_processors::structs::PaymentProcessorDTO {
     fn from(val: PaymentDTO) -> Self {
         payment_processors::structs::PaymentProcessorDTO {
             correlation_id: val.correlation_id,
-            amount: val.amount , // Convert to cents
+            amount: val.amount,
             requested_at: Utc::now(),
         }
     }
@@ -54,10 +59,15 @@ impl From<PaymentDTO> for payment_processors::structs::PaymentProcessorDTO {
 
 #[derive(Clone)]
 pub struct AppState {
-    pub database: crate::db::PostgresDatabase,
-    pub memory_database: crate::db::MemoryDatabase,
+    pub config: Arc<crate::config::Config>,
+    pub payments: Arc<dyn crate::storage::PaymentStore>,
+    pub queue: Arc<dyn crate::storage::WorkQueue>,
+    pub dead_letters: Arc<dyn crate::storage::DeadLetterStore>,
+    pub health_bus: Arc<dyn crate::storage::HealthBus>,
     pub http_client: reqwest::Client,
-    pub redis_queue: crate::queue::RedisQueue,
-    pub processor_health: Arc<RwLock<payment_processors::structs::PaymentProcessorHealth>>,
-    pub health_check_channel: crate::pubsub::HealthCheckChannel,
-}
\ No newline at end of file
+    pub payment_router: payment_processors::routing::PaymentRouter,
+    pub processor_health: Arc<crate::health::ProcessorHealthState>,
+    pub shutdown: crate::shutdown::Shutdown,
+    pub in_flight: Arc<crate::shutdown::InFlightPayments>,
+    pub admission: Arc<crate::admission::AdmissionQueue>,
+}
@@ -0,0 +1,142 @@
+use std::str::FromStr;
+
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+
+use crate::structs::PaymentsSummaryResponseDTO;
+
+const MICROS_PER_SECOND: i64 = 1_000_000;
+
+/// Where `/payments-summary` reads from. Both are always written, so either can answer.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum SummaryBackend {
+    /// Flushes the Redis buffer and aggregates the `transactions` table.
+    Postgres,
+    /// Sums the per-second counters kept in Redis, without touching Postgres.
+    Redis,
+}
+
+impl FromStr for SummaryBackend {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "postgres" => Ok(SummaryBackend::Postgres),
+            "redis" => Ok(SummaryBackend::Redis),
+            _ => Err("expected postgres or redis".to_string()),
+        }
+    }
+}
+
+/// The per-second buckets covering an inclusive `from..=to` range, at microsecond precision
+/// to match what Postgres stores.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BucketRange {
+    /// Microsecond bounds, `None` when unbounded.
+    pub from_us: Option<i64>,
+    pub to_us: Option<i64>,
+    /// Seconds that lie wholly inside the range, summed from their counters.
+    pub first_full_second: Option<i64>,
+    pub last_full_second: Option<i64>,
+    /// Seconds the range only partly covers, summed from their individual payments.
+    pub partial_seconds: Vec<i64>,
+}
+
+impl BucketRange {
+    pub fn new(from: Option<DateTime<Utc>>, to: Option<DateTime<Utc>>) -> Self {
+        let from_us = from.map(|from| from.timestamp_micros());
+        let to_us = to.map(|to| to.timestamp_micros());
+
+        let mut partial_seconds = Vec::new();
+        let first_full_second = from_us.map(|from_us| {
+            let second = from_us.div_euclid(MICROS_PER_SECOND);
+            if from_us.rem_euclid(MICROS_PER_SECOND) == 0 {
+                second
+            } else {
+                partial_seconds.push(second);
+                second + 1
+            }
+        });
+        let last_full_second = to_us.map(|to_us| {
+            let second = to_us.div_euclid(MICROS_PER_SECOND);
+            if (to_us + 1).rem_euclid(MICROS_PER_SECOND) == 0 {
+                second
+            } else {
+                if !partial_seconds.contains(&second) {
+                    partial_seconds.push(second);
+                }
+                second - 1
+            }
+        });
+
+        Self {
+            from_us,
+            to_us,
+            first_full_second,
+            last_full_second,
+            partial_seconds,
+        }
+    }
+}
+
+/// Both summaries for the same range, and whether they agree.
+#[derive(Debug, Clone, Serialize)]
+pub struct SummaryConsistency {
+    pub consistent: bool,
+    pub postgres: PaymentsSummaryResponseDTO,
+    pub redis: PaymentsSummaryResponseDTO,
+}
+
+impl SummaryConsistency {
+    pub fn new(postgres: PaymentsSummaryResponseDTO, redis: PaymentsSummaryResponseDTO) -> Self {
+        Self {
+            consistent: postgres == redis,
+            postgres,
+            redis,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn at(micros: i64) -> Option<DateTime<Utc>> {
+        DateTime::from_timestamp_micros(micros)
+    }
+
+    #[test]
+    fn splits_range_into_full_and_partial_seconds() {
+        const S: i64 = MICROS_PER_SECOND;
+
+        type Bound = Option<DateTime<Utc>>;
+        type Expected = (Option<i64>, Option<i64>, &'static [i64]);
+        #[rustfmt::skip]
+        let table: &[(Bound, Bound, Expected)] = &[
+            // from             to                      first     last      partial
+            (None,              None,                   (None,    None,     &[])),
+            (at(10 * S),        at(20 * S - 1),         (Some(10), Some(19), &[])),
+            (at(10 * S),        at(20 * S),             (Some(10), Some(19), &[20])),
+            (at(10 * S + 500),  at(20 * S - 1),         (Some(11), Some(19), &[10])),
+            (at(10 * S + 500),  at(20 * S + 500),       (Some(11), Some(19), &[10, 20])),
+            (at(10 * S + 1),    at(10 * S + 900),       (Some(11), Some(9),  &[10])),
+            (at(10 * S + 1),    None,                   (Some(11), None,     &[10])),
+            (None,              at(10 * S + 1),         (None,    Some(9),  &[10])),
+            (at(-S / 2),        at(S / 2),              (Some(0), Some(-1), &[-1, 0])),
+        ];
+
+        for (from, to, (first, last, partial)) in table {
+            let range = BucketRange::new(*from, *to);
+            assert_eq!(
+                (
+                    range.first_full_second,
+                    range.last_full_second,
+                    range.partial_seconds.as_slice()
+                ),
+                (*first, *last, *partial),
+                "{from:?}..={to:?}"
+            );
+        }
+    }
+}
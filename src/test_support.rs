@@ -0,0 +1,73 @@
+//! An `AppState` for tests, on in-memory storage and fake processors.
+
+use std::sync::Arc;
+
+use axum::{
+    Router,
+    http::StatusCode,
+    routing::{get, post},
+};
+
+use crate::{
+    admission::AdmissionQueue,
+    config::Config,
+    health::ProcessorHealthState,
+    payment_processors::routing::PaymentRouter,
+    shutdown::{InFlightPayments, Shutdown},
+    storage,
+    structs::AppState,
+};
+
+/// A processor that answers every payment sent to it with `payment` and every payment
+/// lookup with `lookup`. Returns its URL.
+async fn processor(payment: StatusCode, lookup: StatusCode) -> String {
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let url = format!("http://{}", listener.local_addr().unwrap());
+    let app = Router::new()
+        .route("/payments", post(move || async move { payment }))
+        .route(
+            "/payments/{correlation_id}",
+            get(move || async move { lookup }),
+        );
+    tokio::spawn(async move { axum::serve(listener, app).await });
+    url
+}
+
+pub fn state(config: Config) -> AppState {
+    let storage = storage::memory::connect(&config);
+    AppState {
+        payment_router: PaymentRouter::from_config(
+            &config.routing,
+            &config.processors,
+            storage.circuit_breaker.clone(),
+        ),
+        config: Arc::new(config),
+        payments: storage.payments,
+        queue: storage.queue,
+        dead_letters: storage.dead_letters,
+        health_bus: storage.health_bus,
+        http_client: reqwest::Client::new(),
+        processor_health: Arc::new(ProcessorHealthState::default()),
+        shutdown: Shutdown::default(),
+        in_flight: Arc::new(InFlightPayments::default()),
+        admission: Arc::new(AdmissionQueue::new(1)),
+    }
+}
+
+/// A state whose processors answer payment lookups with `default` and `fallback`.
+pub async fn state_with_lookups(default: StatusCode, fallback: StatusCode) -> AppState {
+    let unused = StatusCode::INTERNAL_SERVER_ERROR;
+    state_with_processors((unused, default), (unused, fallback)).await
+}
+
+/// A state whose processors answer payments and lookups with the `(payment, lookup)`
+/// statuses given for `default` and `fallback`.
+pub async fn state_with_processors(
+    default: (StatusCode, StatusCode),
+    fallback: (StatusCode, StatusCode),
+) -> AppState {
+    let mut config = Config::default();
+    config.processors.default_url = processor(default.0, default.1).await;
+    config.processors.fallback_url = processor(fallback.0, fallback.1).await;
+    state(config)
+}
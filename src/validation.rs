@@ -0,0 +1,203 @@
+use axum::{
+    body::Bytes,
+    extract::{FromRequest, Request},
+    http::{HeaderMap, header},
+};
+use serde::de::DeserializeOwned;
+use serde_json::error::Category;
+
+use crate::{
+    error_handling::AppError,
+    structs::{DeadLetterQuery, PaymentDTO},
+};
+
+/// Checks a request body after it has been deserialized.
+pub trait Validate {
+    fn validate(&self) -> Result<(), AppError>;
+}
+
+impl Validate for PaymentDTO {
+    fn validate(&self) -> Result<(), AppError> {
+        if self.correlation_id.is_nil() {
+            return Err(AppError::validation(
+                "invalid_correlation_id",
+                "correlationId must not be the nil UUID",
+                "correlationId",
+            ));
+        }
+        if self.amount.cents() <= 0 {
+            return Err(AppError::validation(
+                "invalid_amount",
+                "amount must be greater than zero",
+                "amount",
+            ));
+        }
+        Ok(())
+    }
+}
+
+impl Validate for DeadLetterQuery {
+    fn validate(&self) -> Result<(), AppError> {
+        if self.limit == Some(0) {
+            return Err(AppError::validation(
+                "invalid_limit",
+                "limit must be greater than zero",
+                "limit",
+            ));
+        }
+        Ok(())
+    }
+}
+
+/// Like `axum::Json`, but runs `Validate` and reports every rejection as an `AppError`.
+#[derive(Debug, Clone)]
+pub struct ValidatedJson<T>(pub T);
+
+impl<T, S> FromRequest<S> for ValidatedJson<T>
+where
+    T: DeserializeOwned + Validate,
+    S: Send + Sync,
+{
+    type Rejection = AppError;
+
+    async fn from_request(req: Request, state: &S) -> Result<Self, Self::Rejection> {
+        if !is_json_content_type(req.headers()) {
+            return Err(AppError::UnsupportedMediaType(
+                "Expected request with `Content-Type: application/json`".to_string(),
+            ));
+        }
+
+        let bytes = Bytes::from_request(req, state)
+            .await
+            .map_err(|e| AppError::BadRequest {
+                code: "invalid_body",
+                message: e.body_text(),
+            })?;
+
+        let value = parse_json::<T>(&bytes)?;
+        value.validate()?;
+        Ok(ValidatedJson(value))
+    }
+}
+
+fn parse_json<T: DeserializeOwned>(bytes: &[u8]) -> Result<T, AppError> {
+    let mut deserializer = serde_json::Deserializer::from_slice(bytes);
+    let value = serde_path_to_error::deserialize(&mut deserializer).map_err(|e| {
+        let path = e.path().to_string();
+        let inner = e.into_inner();
+        match inner.classify() {
+            Category::Data => {
+                let message = inner.to_string();
+                let field = if path == "." {
+                    missing_field(&message)
+                } else {
+                    Some(path)
+                };
+                AppError::Validation {
+                    code: if field.is_some() && message.starts_with("missing field") {
+                        "missing_field"
+                    } else {
+                        "invalid_field"
+                    },
+                    message,
+                    field,
+                }
+            }
+            Category::Syntax | Category::Eof | Category::Io => AppError::BadRequest {
+                code: "malformed_json",
+                message: inner.to_string(),
+            },
+        }
+    })?;
+
+    deserializer.end().map_err(|e| AppError::BadRequest {
+        code: "malformed_json",
+        message: e.to_string(),
+    })?;
+    Ok(value)
+}
+
+// serde reports a missing field against its parent, so the name only shows up in the message.
+fn missing_field(message: &str) -> Option<String> {
+    let rest = message.strip_prefix("missing field `")?;
+    let (field, _) = rest.split_once('`')?;
+    Some(field.to_string())
+}
+
+fn is_json_content_type(headers: &HeaderMap) -> bool {
+    let Some(content_type) = headers
+        .get(header::CONTENT_TYPE)
+        .and_then(|value| value.to_str().ok())
+    else {
+        return false;
+    };
+
+    let mime = content_type
+        .split(';')
+        .next()
+        .unwrap_or_default()
+        .trim()
+        .to_ascii_lowercase();
+    mime == "application/json" || (mime.starts_with("application/") && mime.ends_with("+json"))
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::{Router, body::Body, http::StatusCode, routing::post};
+    use tower::ServiceExt;
+
+    use super::*;
+
+    async fn accept(ValidatedJson(_): ValidatedJson<PaymentDTO>) -> StatusCode {
+        StatusCode::ACCEPTED
+    }
+
+    async fn post_payment(content_type: &str, body: &str) -> (StatusCode, serde_json::Value) {
+        let request = Request::post("/payments")
+            .header(header::CONTENT_TYPE, content_type)
+            .body(Body::from(body.to_string()))
+            .unwrap();
+        let response = Router::new()
+            .route("/payments", post(accept))
+            .oneshot(request)
+            .await
+            .unwrap();
+
+        let status = response.status();
+        let bytes = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        let body = serde_json::from_slice(&bytes).unwrap_or(serde_json::Value::Null);
+        (status, body)
+    }
+
+    #[tokio::test]
+    async fn rejects_payments_with_error_envelope() {
+        const JSON: &str = "application/json";
+        const ID: &str = "4a7901b8-7d26-4d9d-aa19-4dc1c7cf60b3";
+        let payment = |amount: &str| format!(r#"{{"correlationId":"{ID}","amount":{amount}}}"#);
+
+        type Expected = Option<(&'static str, Option<&'static str>)>;
+        #[rustfmt::skip]
+        let table: Vec<(&str, String, StatusCode, Expected)> = vec![
+            (JSON, payment("19.90"), StatusCode::ACCEPTED, None),
+            ("text/plain", payment("19.90"), StatusCode::UNSUPPORTED_MEDIA_TYPE, Some(("unsupported_media_type", None))),
+            (JSON, "{".to_string(), StatusCode::BAD_REQUEST, Some(("malformed_json", None))),
+            (JSON, payment("0"), StatusCode::UNPROCESSABLE_ENTITY, Some(("invalid_amount", Some("amount")))),
+            (JSON, payment("-5"), StatusCode::UNPROCESSABLE_ENTITY, Some(("invalid_field", Some("amount")))),
+            (JSON, payment("1.234"), StatusCode::UNPROCESSABLE_ENTITY, Some(("invalid_field", Some("amount")))),
+            (JSON, format!(r#"{{"correlationId":"{ID}"}}"#), StatusCode::UNPROCESSABLE_ENTITY, Some(("missing_field", Some("amount")))),
+            (JSON, r#"{"correlationId":"nope","amount":1}"#.to_string(), StatusCode::UNPROCESSABLE_ENTITY, Some(("invalid_field", Some("correlationId")))),
+        ];
+
+        for (content_type, body, expected_status, expected_error) in table {
+            let (status, envelope) = post_payment(content_type, &body).await;
+            assert_eq!(status, expected_status, "{body}");
+            if let Some((code, field)) = expected_error {
+                assert_eq!(envelope["code"], code, "{body}");
+                assert_eq!(envelope["field"].as_str(), field, "{body}");
+                assert!(envelope["message"].is_string(), "{body}");
+            }
+        }
+    }
+}
@@ -0,0 +1,159 @@
+use std::{sync::Arc, time::Duration};
+
+use crate::{
+    dead_letter::DeadLetter,
+    queue::QueueDelivery,
+    retry::Backoff,
+    service::{self, PaymentDeadline, PaymentOutcome},
+    structs::AppState,
+};
+
+// How long a worker waits before looking again when there was nothing it could do.
+const IDLE_SLEEP: Duration = Duration::from_millis(10);
+
+/// Takes payments off the queue and processes them until shutdown. A payment that isn't
+/// processed goes back through the delayed set with backoff, until it runs out of attempts.
+pub async fn run_worker(state: Arc<AppState>, consumer: String) {
+    let backoff = Backoff::from_config(&state.config.queue);
+    while !state.shutdown.is_triggered() {
+        let routable = {
+            let health = state.processor_health.read().await;
+            service::select_service(&state.payment_router, &health).is_some()
+        };
+        if !routable {
+            tokio::time::sleep(IDLE_SLEEP).await;
+            continue;
+        }
+        match state.queue.pop(&consumer).await {
+            Ok(Some(mut delivery)) => handle(&state, &mut delivery, backoff).await,
+            Ok(None) => tokio::time::sleep(IDLE_SLEEP).await,
+            Err(e) => {
+                eprintln!("Failed to pop payment: {e:?}");
+                tokio::time::sleep(IDLE_SLEEP).await;
+            }
+        }
+    }
+}
+
+async fn handle(state: &AppState, delivery: &mut QueueDelivery, backoff: Backoff) {
+    let deadline = PaymentDeadline::since(
+        delivery.budget_started_at,
+        state.payment_router.payment_budget,
+    );
+    let processed = if deadline.is_expired() {
+        Ok(PaymentOutcome::DeadlineExceeded)
+    } else {
+        service::process_payment(
+            state,
+            delivery.payment,
+            &mut delivery.suspects,
+            deadline,
+            None,
+        )
+        .await
+    };
+    let (status, error) = match processed {
+        Ok(PaymentOutcome::Processed(_)) => {
+            if let Err(e) = state.queue.ack(delivery).await {
+                eprintln!("Failed to ack payment: {e:?}");
+            }
+            return;
+        }
+        Ok(PaymentOutcome::Rejected(error)) => {
+            eprintln!(
+                "Payment {} rejected by the processor: {error}",
+                delivery.payment.correlation_id
+            );
+            let dead_letter = DeadLetter::new(
+                delivery.payment,
+                delivery.suspects,
+                delivery.attempts + 1,
+                error.status().as_u16(),
+                error.to_string(),
+            );
+            service::dead_letter_delivery(state, delivery, &dead_letter).await;
+            return;
+        }
+        // The budget covers every attempt, so there is no retrying past it.
+        Ok(PaymentOutcome::DeadlineExceeded) => {
+            let dead_letter = DeadLetter::deadline_exceeded(
+                delivery.payment,
+                delivery.suspects,
+                delivery.attempts + 1,
+            );
+            service::dead_letter_delivery(state, delivery, &dead_letter).await;
+            return;
+        }
+        Ok(PaymentOutcome::NotProcessed) => (503, "No processor took the payment".to_string()),
+        Err(e) => (e.status().as_u16(), e.to_string()),
+    };
+
+    // Hand the payment to another instance rather than hold on to it.
+    if state.shutdown.is_triggered() {
+        if let Err(e) = state.queue.nack(delivery).await {
+            eprintln!("Failed to requeue payment: {e:?}");
+        }
+        return;
+    }
+    let attempts = delivery.attempts + 1;
+    if attempts >= state.config.queue.max_attempts {
+        eprintln!(
+            "Failed to process payment {} after {attempts} attempts: {error}",
+            delivery.payment.correlation_id
+        );
+        let dead_letter =
+            DeadLetter::new(delivery.payment, delivery.suspects, attempts, status, error);
+        service::dead_letter_delivery(state, delivery, &dead_letter).await;
+        return;
+    }
+    // If this fails the delivery stays in flight, and the reaper requeues it once it expires.
+    if let Err(e) = state
+        .queue
+        .retry_later(delivery, backoff.delay(delivery.attempts))
+        .await
+    {
+        eprintln!("Failed to schedule a retry of payment: {e:?}");
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use uuid::Uuid;
+
+    use super::*;
+    use crate::{
+        config::Config, money::Money, payment_processors::structs::PaymentProcessorDTO,
+        queue::QueuedPayment, test_support,
+    };
+
+    #[tokio::test]
+    async fn dead_letters_payments_that_spent_their_budget() {
+        let state = test_support::state(Config::default());
+        let payment = PaymentProcessorDTO {
+            correlation_id: Uuid::from_u128(1),
+            amount: Money::from_cents(100),
+            requested_at: "2025-07-01T12:00:00Z".parse().unwrap(),
+        };
+        state.queue.push(QueuedPayment::new(payment)).await.unwrap();
+
+        let mut delivery = state.queue.pop("worker").await.unwrap().unwrap();
+        handle(
+            &state,
+            &mut delivery,
+            Backoff::from_config(&state.config.queue),
+        )
+        .await;
+
+        let dead_letter = state
+            .dead_letters
+            .get(payment.correlation_id)
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(
+            (dead_letter.attempts, dead_letter.last_error_status),
+            (1, 504)
+        );
+        assert_eq!(state.queue.depth().await.unwrap(), 0);
+    }
+}